@@ -1,4 +1,3 @@
-use toy_kv::engine::kv;
 use toy_kv::transport::open_db_from;
 
 use tempfile::tempdir;
@@ -6,16 +5,12 @@ use time::PreciseTime;
 
 fn main() {
     let tmp = tempdir().unwrap();
-    let mut db = open_db_from(&tmp.into_path()).unwrap();
+    let db = open_db_from(&tmp.into_path()).unwrap();
     let start = PreciseTime::now();
     for _ in 0..100_000 {
         let k = gen_rand_string(8);
         let v = gen_rand_string(256);
-        db.put(
-            k.parse().unwrap(),
-            kv::Value::Valid(Box::new(v.parse().unwrap())),
-        )
-        .unwrap();
+        db.put(k.as_bytes(), v.as_bytes()).unwrap();
     }
     let end = PreciseTime::now();
     println!(
@@ -1,4 +1,3 @@
-use toy_kv::engine::kv;
 use toy_kv::transport::open_db_from;
 
 use tempfile::tempdir;
@@ -6,14 +5,11 @@ use time::PreciseTime;
 
 fn main() {
     let tmp = tempdir().unwrap();
-    let mut db = open_db_from(&tmp.into_path()).unwrap();
+    let db = open_db_from(&tmp.into_path()).unwrap();
     let start = PreciseTime::now();
     for i in 0..100_000 {
-        db.put(
-            format!("k{}", i).parse().unwrap(),
-            kv::Value::Valid(Box::new(format!("v{}", i).parse().unwrap())),
-        )
-        .unwrap();
+        db.put(format!("k{}", i).as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
     }
     let end = PreciseTime::now();
     println!(
@@ -23,7 +19,7 @@ fn main() {
             / (start.to(end).num_nanoseconds().unwrap() as f64 / 1e9) as f64
     );
     for i in 0..100_000 {
-        let v = db.get(format!("k{}", i).parse().unwrap()).unwrap().unwrap();
-        assert_eq!(v.to_string(), format!("v{}", i));
+        let v = db.get(format!("k{}", i).as_bytes()).unwrap().unwrap();
+        assert_eq!(v, format!("v{}", i).as_bytes());
     }
 }
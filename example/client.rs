@@ -46,9 +46,16 @@ fn main() {
                     println!("Running toy client");
                     println!("Usage: $CMD [KEY?] [VALUE?], ie");
                     println!("\t Get [key]");
+                    println!("\t MGet [key] [key...]");
                     println!("\t Put [key] [value]");
                     println!("\t Delete [key]");
                     println!("\t Scan");
+                    println!("\t ScanPrefix [prefix]");
+                    println!("\t Cas [key] [expected|-] [new|-] (`-` for absent)");
+                    println!("\t Checkpoint [dir] (under the backup root of the server)");
+                    println!(
+                        "\t Export [since] [delta file] (under the backup root of the server)"
+                    );
                     futures::future::ok(())
                 })
                 .map_err(|e| {
@@ -110,6 +117,14 @@ impl Handler<ClientCommand> for ToyClient {
             } else {
                 eprintln!("Wrong format, try `Get [key]`");
             }
+        } else if cmd == "MGet" {
+            if v.len() >= 2 {
+                self.framed.write(codec::ToyRequest::MGet(
+                    v[1..].iter().map(|k| (*k).to_owned()).collect(),
+                ));
+            } else {
+                eprintln!("Wrong format, try `MGet [key] [key...]`");
+            }
         } else if cmd == "Put" {
             let v: Vec<&str> = m.split(' ').collect();
             if v.len() == 3 {
@@ -128,6 +143,39 @@ impl Handler<ClientCommand> for ToyClient {
             }
         } else if m == "Scan" {
             self.framed.write(codec::ToyRequest::Scan);
+        } else if cmd == "ScanPrefix" {
+            if v.len() == 2 {
+                self.framed
+                    .write(codec::ToyRequest::ScanPrefix(v[1].to_owned()));
+            } else {
+                eprintln!("Wrong format, try `ScanPrefix [prefix]`");
+            }
+        } else if cmd == "Cas" {
+            if v.len() == 4 {
+                // `-` stands for an absent key
+                let opt = |s: &str| if s == "-" { None } else { Some(s.to_owned()) };
+                self.framed.write(codec::ToyRequest::Cas((
+                    v[1].to_owned(),
+                    opt(v[2]),
+                    opt(v[3]),
+                )));
+            } else {
+                eprintln!("Wrong format, try `Cas [key] [expected|-] [new|-]`");
+            }
+        } else if cmd == "Checkpoint" {
+            if v.len() == 2 {
+                self.framed
+                    .write(codec::ToyRequest::Checkpoint(v[1].to_owned()));
+            } else {
+                eprintln!("Wrong format, try `Checkpoint [dir]`");
+            }
+        } else if cmd == "Export" {
+            match (v.len(), v.get(1).map(|since| since.parse::<u64>())) {
+                (3, Some(Ok(since))) => self
+                    .framed
+                    .write(codec::ToyRequest::Export((since, v[2].to_owned()))),
+                _ => eprintln!("Wrong format, try `Export [since] [delta file]`"),
+            }
         } else {
             eprintln!("Unknown command!")
         }
@@ -145,12 +193,33 @@ impl StreamHandler<codec::ToyResponse, io::Error> for ToyClient {
                     println!("{}", msg);
                 }
             }
+            codec::ToyResponse::Values(ref msg) => {
+                for (key, value) in msg {
+                    match value {
+                        Some(value) => println!("({}, {})", key, value),
+                        None => println!("key({}) not found", key),
+                    }
+                }
+            }
             codec::ToyResponse::Saved(ref msg) => {
                 println!("({}, {}) saved", msg.0, msg.1);
             }
             codec::ToyResponse::Deleted(ref msg) => {
                 println!("key({}) deleted", msg);
             }
+            codec::ToyResponse::Swapped(ref msg) => {
+                if msg.1 {
+                    println!("key({}) swapped", msg.0);
+                } else {
+                    println!("key({}) not swapped", msg.0);
+                }
+            }
+            codec::ToyResponse::Checkpointed(ref msg) => {
+                println!("checkpoint({}) at ventry {}", msg.0, msg.1);
+            }
+            codec::ToyResponse::Exported(ref msg) => {
+                println!("delta({}) up to ventry {}", msg.0, msg.1);
+            }
             codec::ToyResponse::Next(ref msg) => {
                 println!("({}, {})", msg.0, msg.1);
             }
@@ -48,6 +48,8 @@ impl Handler<TcpConnect> for Server {
 /// Environment
 static DB_DIR: &str = "DB_DIR";
 static SERVER_PORT: &str = "SERVER_PORT";
+/// Where clients write checkpoints and deltas, `$DB_DIR/backups` by default
+static BACKUP_DIR: &str = "BACKUP_DIR";
 
 fn main() {
     let db_dir: PathBuf = env::var(DB_DIR)
@@ -55,10 +57,16 @@ fn main() {
         .parse()
         .unwrap();
     let port = env::var(SERVER_PORT).unwrap_or_else(|_| "8888".to_owned());
+    let backup_dir = env::var(BACKUP_DIR).ok();
 
     actix::System::run(move || {
         // Start toy server actor
-        let server = ToyServer::new(db_dir).start();
+        let server = ToyServer::new(&db_dir);
+        let server = match backup_dir {
+            Some(dir) => server.with_backup_root(dir),
+            None => server,
+        }
+        .start();
 
         // Create server listener
         use std::net::{IpAddr, Ipv4Addr, SocketAddr};
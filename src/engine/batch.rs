@@ -0,0 +1,44 @@
+use super::kv::Value;
+
+/// A group of writes applied atomically by `Store::write`
+/// Operations are applied in the order they were added
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, Value)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), Value::Valid(value.to_vec())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), Value::Invalid));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(Vec<u8>, Value)> {
+        self.ops.iter()
+    }
+
+    pub(crate) fn into_iter(self) -> impl Iterator<Item = (Vec<u8>, Value)> {
+        self.ops.into_iter()
+    }
+}
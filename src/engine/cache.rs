@@ -0,0 +1,170 @@
+use super::dio::{Block4k, DirectFile};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// size of a cached block, the alignment of direct io
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// Hit and miss counters of a `BlockCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// LRU cache of aligned blocks of the values file, shared by all reads
+/// Blocks of the values file never change once written, so they are never invalidated.
+pub struct BlockCache {
+    // in blocks
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Lru {
+    // block number -> (block, last used)
+    blocks: HashMap<u64, (Box<Block4k>, u64)>,
+    // last used -> block number, the first one is evicted
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, n: u64) -> Option<&Block4k> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (block, used) = self.blocks.get_mut(&n)?;
+        self.order.remove(used);
+        self.order.insert(tick, n);
+        *used = tick;
+        Some(block)
+    }
+
+    fn insert(&mut self, n: u64, block: Box<Block4k>, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, used)) = self.blocks.insert(n, (block, self.tick)) {
+            // Loaded by another reader meanwhile
+            self.order.remove(&used);
+        }
+        self.order.insert(self.tick, n);
+        while self.blocks.len() > capacity {
+            let (&used, &oldest) = self.order.iter().next().unwrap();
+            self.order.remove(&used);
+            self.blocks.remove(&oldest);
+        }
+    }
+}
+
+impl BlockCache {
+    /// A cache holding at most `size` bytes of blocks
+    pub fn new(size: usize) -> Self {
+        BlockCache {
+            capacity: size / BLOCK_SIZE as usize,
+            lru: Mutex::new(Lru {
+                blocks: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Read `len` bytes at `offset` of `file`, through the cache
+    /// A read spanning more blocks than the cache holds bypasses it.
+    pub fn read(&self, file: &DirectFile, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset + len as u64;
+        let first = offset / BLOCK_SIZE;
+        let last = (end - 1) / BLOCK_SIZE;
+        if (last - first + 1) as usize > self.capacity {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return file.read_at(offset, len);
+        }
+        let mut bytes = Vec::with_capacity(len);
+        for n in first..=last {
+            let start = offset.max(n * BLOCK_SIZE) - n * BLOCK_SIZE;
+            let stop = end.min((n + 1) * BLOCK_SIZE) - n * BLOCK_SIZE;
+            self.with_block(file, n, |block| {
+                bytes.extend_from_slice(&block[start as usize..stop as usize])
+            })?;
+        }
+        Ok(bytes)
+    }
+
+    /// Call `f` with the block `n`, loading it on a miss
+    fn with_block<F: FnOnce(&[u8])>(&self, file: &DirectFile, n: u64, f: F) -> io::Result<()> {
+        if let Some(block) = self.lru.lock().unwrap().touch(n) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            f(&block.bytes);
+            return Ok(());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Read without holding the cache
+        let mut block = Box::new(Block4k {
+            bytes: [0; BLOCK_SIZE as usize],
+        });
+        if file.pread(&mut block.bytes, n * BLOCK_SIZE)? < BLOCK_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        f(&block.bytes);
+        self.lru.lock().unwrap().insert(n, block, self.capacity);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::dio::{FileAccess, Mode};
+    use super::*;
+    use tempfile::tempdir;
+
+    fn tmpfile(name: &str, blocks: u8) -> DirectFile {
+        let path = tempdir().unwrap().into_path().join(name);
+        let file = DirectFile::open(&path, Mode::Open, FileAccess::ReadWrite, 4096).unwrap();
+        for n in 0..blocks {
+            let block = Block4k { bytes: [n; 4096] };
+            file.pwrite(&block.bytes, u64::from(n) * BLOCK_SIZE)
+                .unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn lru_test() {
+        let file = tmpfile("lru", 4);
+        let cache = BlockCache::new(2 * BLOCK_SIZE as usize);
+        // (block, misses so far), block 0 is evicted by block 2
+        let reads = [(0, 1), (0, 1), (1, 2), (2, 3), (1, 3), (0, 4)];
+        for (n, misses) in reads.iter() {
+            let bytes = cache.read(&file, n * BLOCK_SIZE + 10, 8).unwrap();
+            assert_eq!(bytes, [*n as u8; 8]);
+            assert_eq!(cache.stats().misses, *misses);
+        }
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4 });
+    }
+
+    #[test]
+    fn span_test() {
+        let file = tmpfile("span", 4);
+        let cache = BlockCache::new(2 * BLOCK_SIZE as usize);
+        let bytes = cache.read(&file, BLOCK_SIZE - 2, 4).unwrap();
+        assert_eq!(bytes, [0, 0, 1, 1]);
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+        // Larger than the cache
+        let bytes = cache.read(&file, 0, 3 * BLOCK_SIZE as usize).unwrap();
+        assert_eq!(bytes.len(), 3 * BLOCK_SIZE as usize);
+        assert_eq!(bytes[2 * BLOCK_SIZE as usize], 2);
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 3 });
+    }
+}
@@ -0,0 +1,296 @@
+use super::error::Error;
+use super::kv::*;
+use super::util;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Leading bytes of every delta file
+pub const DELTA_MAGIC: &[u8; 8] = b"TOYDELTA";
+/// Version of the delta format, bumped on incompatible changes
+pub const DELTA_VERSION: u32 = 1;
+/// Delta header: magic(8 bytes) + crc(4 bytes) + version(4 bytes)
+/// + from ventry(8 bytes) + to ventry(8 bytes) + number of records(8 bytes)
+pub const DELTA_HEADER_SIZE: usize = 40;
+
+/// Header of a delta file: the writes of ventries `from..to`
+/// The records follow it, each a key record(its value pointer unused)
+/// then the value record, in the formats of the keys and values files.
+/// crc covers the rest of the header, each record has its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaHeader {
+    pub version: u32,
+    /// ventry of the first write, the watermark of the previous backup
+    pub from: u64,
+    /// ventry after the last write, the watermark of the next delta
+    pub to: u64,
+    /// number of records
+    pub count: u64,
+}
+
+impl DeltaHeader {
+    pub fn to_bytes(&self) -> [u8; DELTA_HEADER_SIZE] {
+        let mut bytes = [0u8; DELTA_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(DELTA_MAGIC);
+        BigEndian::write_u32(&mut bytes[12..16], self.version);
+        BigEndian::write_u64(&mut bytes[16..24], self.from);
+        BigEndian::write_u64(&mut bytes[24..32], self.to);
+        BigEndian::write_u64(&mut bytes[32..DELTA_HEADER_SIZE], self.count);
+        let crc = crc32fast::hash(&bytes[12..]);
+        BigEndian::write_u32(&mut bytes[8..12], crc);
+        bytes
+    }
+
+    /// Parse a delta header, `Err` describes why it is not one
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < DELTA_HEADER_SIZE || &bytes[0..8] != DELTA_MAGIC {
+            return Err("not a toy-kv delta".to_owned());
+        }
+        let bytes = &bytes[..DELTA_HEADER_SIZE];
+        if BigEndian::read_u32(&bytes[8..12]) != crc32fast::hash(&bytes[12..]) {
+            return Err("corrupted header".to_owned());
+        }
+        let version = BigEndian::read_u32(&bytes[12..16]);
+        if version != DELTA_VERSION {
+            return Err(format!(
+                "unsupported delta version {}, expected {}",
+                version, DELTA_VERSION
+            ));
+        }
+        let header = DeltaHeader {
+            version,
+            from: BigEndian::read_u64(&bytes[16..24]),
+            to: BigEndian::read_u64(&bytes[24..32]),
+            count: BigEndian::read_u64(&bytes[32..DELTA_HEADER_SIZE]),
+        };
+        if header.to < header.from || header.to - header.from < header.count {
+            return Err(format!(
+                "{} records between ventries {} and {}",
+                header.count, header.from, header.to
+            ));
+        }
+        Ok(header)
+    }
+}
+
+/// Writes a delta file, see `Store::export_delta`
+/// Written to a tmp file then renamed once the header is written.
+pub struct DeltaWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    tmp: PathBuf,
+    from: u64,
+    count: u64,
+}
+
+impl DeltaWriter {
+    /// Start the delta of the writes since `from`, failing if `path` exists
+    pub fn create<P: AsRef<Path>>(path: P, from: u64) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", path),
+            )
+            .into());
+        }
+        let tmp = util::with_suffix(&path, util::COMPACT_SUFFIX);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        // The header is written once the records are
+        writer.write_all(&[0u8; DELTA_HEADER_SIZE])?;
+        Ok(DeltaWriter {
+            writer,
+            path,
+            tmp,
+            from,
+            count: 0,
+        })
+    }
+
+    /// Append the record of a write, in ventry order
+    pub fn append(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
+        let key = Key {
+            vptr: 0,
+            ..key.clone()
+        };
+        self.writer.write_all(&key_to_bytes(&key))?;
+        self.writer.write_all(&value_to_bytes(value))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Write the header, the delta ends at ventry `to`
+    pub fn finish(self, to: u64) -> Result<DeltaHeader, Error> {
+        let header = DeltaHeader {
+            version: DELTA_VERSION,
+            from: self.from,
+            to,
+            count: self.count,
+        };
+        let mut f = self.writer.into_inner().map_err(|e| e.into_error())?;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&header.to_bytes())?;
+        f.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        util::sync_dir(&self.path)?;
+        Ok(header)
+    }
+}
+
+/// Reads the records of a delta file, in ventry order
+/// A record failing its check, or missing, is reported as `Error::Corrupted`.
+pub struct DeltaReader {
+    reader: BufReader<File>,
+    path: PathBuf,
+    header: DeltaHeader,
+    // Records read so far
+    read: u64,
+    // Offset of the next record
+    offset: u64,
+}
+
+impl DeltaReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut head = Vec::with_capacity(DELTA_HEADER_SIZE);
+        (&mut reader)
+            .take(DELTA_HEADER_SIZE as u64)
+            .read_to_end(&mut head)?;
+        let header = DeltaHeader::from_bytes(&head).map_err(|reason| Error::InvalidDelta {
+            file: path.clone(),
+            reason,
+        })?;
+        Ok(DeltaReader {
+            reader,
+            path,
+            header,
+            read: 0,
+            offset: DELTA_HEADER_SIZE as u64,
+        })
+    }
+
+    pub fn header(&self) -> &DeltaHeader {
+        &self.header
+    }
+
+    fn read_record(&mut self) -> Result<(Key, Value), Error> {
+        let mut record = vec![0u8; KEY_HEADER_SIZE];
+        self.read_exact(&mut record, self.offset)?;
+        let klen = BigEndian::read_u16(&record[5..7]) as usize;
+        if klen == 0 || klen > MAX_KEY_SIZE {
+            return Err(self.corrupted(self.offset));
+        }
+        record.resize(key_record_size(klen), 0);
+        self.read_exact(&mut record[KEY_HEADER_SIZE..], self.offset)?;
+        let key = match key_from_bytes(&record) {
+            Ok(Some(key)) => key,
+            _ => return Err(self.corrupted(self.offset)),
+        };
+
+        let value_offset = self.offset + record.len() as u64;
+        let mut value = vec![0u8; value_record_size(key.vlen)];
+        self.read_exact(&mut value, value_offset)?;
+        let value = value_from_bytes(&value).map_err(|_| self.corrupted(value_offset))?;
+        self.offset = value_offset + value_record_size(key.vlen) as u64;
+        Ok((key, value))
+    }
+
+    /// Read the bytes of the record at `offset`, a truncated file is corrupted
+    fn read_exact(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(self.corrupted(offset)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn corrupted(&self, offset: u64) -> Error {
+        Error::Corrupted {
+            file: self.path.clone(),
+            offset,
+        }
+    }
+}
+
+impl Iterator for DeltaReader {
+    type Item = Result<(Key, Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read == self.header.count {
+            return None;
+        }
+        self.read += 1;
+        Some(self.read_record())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn delta_test() {
+        let path = tempdir().unwrap().into_path().join("test.delta");
+        let key = |raw: &[u8], ventry: u64, flags: u8| Key {
+            inner: InnerKey::from(raw),
+            ventry,
+            vptr: 1024,
+            vlen: 5,
+            flags,
+            expires: 0,
+        };
+        let mut writer = DeltaWriter::create(&path, 7).unwrap();
+        writer
+            .append(&key(b"key", 7, 0), &Value::Valid(b"value".to_vec()))
+            .unwrap();
+        let tombstone = Key {
+            vlen: TOMBSTONE,
+            ..key(b"\0key", 8, FLAG_BATCH | FLAG_BATCH_END)
+        };
+        writer.append(&tombstone, &Value::Invalid).unwrap();
+        let header = writer.finish(9).unwrap();
+        assert_eq!(header.count, 2);
+        assert!(DeltaWriter::create(&path, 9).is_err());
+
+        let reader = DeltaReader::open(&path).unwrap();
+        assert_eq!(reader.header(), &header);
+        let records: Vec<(Key, Value)> = reader.map(Result::unwrap).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0.inner.raw, b"key");
+        assert_eq!(records[0].0.vptr, 0);
+        assert!(matches!(&records[0].1, Value::Valid(v) if v == b"value"));
+        assert_eq!(records[1].0.ventry, 8);
+        assert_eq!(records[1].0.flags, FLAG_BATCH | FLAG_BATCH_END);
+        assert!(matches!(records[1].1, Value::Invalid));
+
+        // A truncated delta is reported at the record cut short
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let mut reader = DeltaReader::open(&path).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        let offset = DELTA_HEADER_SIZE + key_record_size(3) + value_record_size(5);
+        let offset = (offset + key_record_size(4)) as u64;
+        assert_eq!(
+            reader.next().unwrap().err().unwrap(),
+            Error::Corrupted {
+                file: path.clone(),
+                offset,
+            }
+        );
+
+        assert_eq!(
+            DeltaHeader::from_bytes(&[0; DELTA_HEADER_SIZE]),
+            Err("not a toy-kv delta".to_owned())
+        );
+        let mut bytes = header.to_bytes();
+        bytes[20] ^= 1;
+        assert_eq!(
+            DeltaHeader::from_bytes(&bytes),
+            Err("corrupted header".to_owned())
+        );
+    }
+}
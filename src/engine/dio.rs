@@ -2,18 +2,30 @@
 /// Only support linux in theory
 /// Adapted according to
 /// https://github.com/jsgf/libaio-rust/blob/9b6c8d4b1eab31092f24cf6c1330d64b90ad0eaa/src/directio.rs#L1
+use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 
 use std::fs::File;
 use std::io::{self, Seek};
+use std::mem::ManuallyDrop;
 use std::path::Path;
+use std::sync::Mutex;
 
 use libc;
 
+#[cfg(feature = "uring")]
+use io_uring::{opcode, types};
+
+#[cfg(feature = "uring")]
+use super::uring::Ring;
+
 pub struct DirectFile {
     fd: RawFd,
     alignment: usize,
+    // None when io_uring is unavailable, blocking io is used then
+    #[cfg(feature = "uring")]
+    ring: Option<Ring>,
 }
 
 const O_DIRECT: i32 = 0x40000; // For Linux
@@ -65,14 +77,14 @@ impl DirectFile {
                 libc::S_IRUSR | libc::S_IWUSR,
             ),
         };
-        let path = path.as_ref().as_os_str().as_bytes();
-        match retry(|| unsafe {
-            libc::open(path.as_ptr() as *const i8, flags, u32::from(mode)) as isize
-        }) {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        match retry(|| unsafe { libc::open(path.as_ptr(), flags, u32::from(mode)) as isize }) {
             -1 => Err(io::Error::last_os_error()),
             fd => Ok(DirectFile {
                 fd: fd as i32,
                 alignment,
+                #[cfg(feature = "uring")]
+                ring: Ring::new(64).ok(),
             }),
         }
     }
@@ -115,9 +127,188 @@ impl DirectFile {
         }
     }
 
+    /// Flush the written data to disk, along with the file size
+    pub fn sync_data(&self) -> io::Result<()> {
+        if unsafe { libc::fdatasync(self.fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes at any `off`
+    /// The aligned span around it is read into 4k blocks, then copied out
+    pub fn read_at(&self, off: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (start, mut buf) = self.span(off, len);
+        let read = self.pread(buf.as_mut_slice(), start)?;
+        Self::copy_out(&buf, start, read, off, len)
+    }
+
+    /// Read `(off, len)` of each request, submitted in one batch with io_uring
+    pub fn read_many(&self, reqs: &[(u64, usize)]) -> io::Result<Vec<Vec<u8>>> {
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = &self.ring {
+                let mut spans: Vec<_> =
+                    reqs.iter().map(|&(off, len)| self.span(off, len)).collect();
+                let entries = spans
+                    .iter_mut()
+                    .map(|(start, buf)| {
+                        let bytes = buf.as_mut_slice();
+                        opcode::Read::new(
+                            types::Fd(self.fd),
+                            bytes.as_mut_ptr(),
+                            bytes.len() as u32,
+                        )
+                        .offset(*start)
+                        .build()
+                    })
+                    .collect();
+                // The buffers live in `spans` until all reads are waited
+                let ids = match unsafe { ring.submit(entries) } {
+                    Ok(ids) => ids,
+                    Err(e) => {
+                        if !e.settled {
+                            // Reads may still land in them
+                            std::mem::forget(spans);
+                        }
+                        return Err(e.error);
+                    }
+                };
+                let mut reads = Vec::with_capacity(ids.len());
+                let mut in_flight = None;
+                for id in ids {
+                    match ring.wait(id) {
+                        Err(e) if !e.reaped => in_flight = in_flight.or(Some(e.error)),
+                        read => reads.push(read.map_err(|e| e.error)),
+                    }
+                }
+                if let Some(error) = in_flight {
+                    // Reads may still land in them
+                    std::mem::forget(spans);
+                    return Err(error);
+                }
+                return reads
+                    .into_iter()
+                    .zip(spans.iter())
+                    .zip(reqs.iter())
+                    .map(|((read, (start, buf)), &(off, len))| {
+                        Self::copy_out(buf, *start, read? as u64, off, len)
+                    })
+                    .collect();
+            }
+        }
+        reqs.iter()
+            .map(|&(off, len)| self.read_at(off, len))
+            .collect()
+    }
+
+    /// Write `buf` at `off`, without waiting for it with io_uring
+    /// The write must be waited by `wait`, before the file is dropped.
+    pub fn write_async(&self, buf: AlignedBuf, off: u64) -> io::Result<PendingWrite> {
+        #[cfg(feature = "uring")]
+        {
+            if let Some(ring) = &self.ring {
+                let bytes = buf.as_slice();
+                let entry =
+                    opcode::Write::new(types::Fd(self.fd), bytes.as_ptr(), bytes.len() as u32)
+                        .offset(off)
+                        .build();
+                // `buf` is kept by the pending write until waited
+                let ids = match unsafe { ring.submit(vec![entry]) } {
+                    Ok(ids) => ids,
+                    Err(e) => {
+                        if !e.settled {
+                            // The write may still read from it
+                            std::mem::forget(buf);
+                        }
+                        return Err(e.error);
+                    }
+                };
+                return Ok(PendingWrite::new(buf, off, WriteState::Submitted(ids[0])));
+            }
+        }
+        let written = self.pwrite(buf.as_slice(), off)?;
+        Ok(PendingWrite::new(buf, off, WriteState::Done(written)))
+    }
+
+    /// Wait for a write, returns the bytes written
+    /// A write still in flight when the wait fails can be waited again,
+    /// its buffer is leaked if it is dropped before.
+    pub fn wait(&self, pending: &PendingWrite) -> io::Result<usize> {
+        #[cfg_attr(not(feature = "uring"), allow(unused_mut))]
+        let mut state = pending.state.lock().unwrap();
+        match *state {
+            WriteState::Done(written) => Ok(written),
+            #[cfg(feature = "uring")]
+            WriteState::Failed(errno) => Err(io::Error::from_raw_os_error(errno)),
+            #[cfg(feature = "uring")]
+            WriteState::Submitted(id) => {
+                let ring = self.ring.as_ref().expect("submitted without io_uring");
+                match ring.wait(id) {
+                    Ok(written) => {
+                        *state = WriteState::Done(written);
+                        Ok(written)
+                    }
+                    Err(e) => {
+                        if e.reaped {
+                            let errno = e.error.raw_os_error().unwrap_or(libc::EIO);
+                            *state = WriteState::Failed(errno);
+                        }
+                        Err(e.error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether reads and writes go through io_uring
+    pub fn uses_uring(&self) -> bool {
+        #[cfg(feature = "uring")]
+        {
+            self.ring.is_some()
+        }
+        #[cfg(not(feature = "uring"))]
+        {
+            false
+        }
+    }
+
+    /// The span of `alignment` blocks holding `len` bytes at `off`
+    fn span(&self, off: u64, len: usize) -> (u64, AlignedBuf) {
+        let alignment = self.alignment as u64;
+        let start = off - off % alignment;
+        let end = (off + len as u64).div_ceil(alignment) * alignment;
+        (start, AlignedBuf::new((end - start) as usize))
+    }
+
+    fn copy_out(
+        buf: &AlignedBuf,
+        start: u64,
+        read: u64,
+        off: u64,
+        len: usize,
+    ) -> io::Result<Vec<u8>> {
+        if start + read < off + len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let pos = (off - start) as usize;
+        Ok(Vec::from(&buf.as_slice()[pos..pos + len]))
+    }
+
     pub fn end_pos(&self) -> usize {
         let mut f = unsafe { File::from_raw_fd(self.fd) };
-        f.seek(io::SeekFrom::End(0)).unwrap() as usize
+        let end = f.seek(io::SeekFrom::End(0)).unwrap() as usize;
+        // The fd is still owned by self
+        let _ = f.into_raw_fd();
+        end
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
     }
 }
 
@@ -126,6 +317,81 @@ pub struct Block4k {
     pub bytes: [u8; 4096],
 }
 
+/// Owned bytes aligned for direct io, allocated in 4k blocks
+pub struct AlignedBuf {
+    blocks: Vec<Block4k>,
+    len: usize,
+}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> Self {
+        AlignedBuf {
+            blocks: (0..len.div_ceil(4096))
+                .map(|_| Block4k { bytes: [0; 4096] })
+                .collect(),
+            len,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+enum WriteState {
+    Done(usize),
+    // The write completed with this errno
+    #[cfg(feature = "uring")]
+    Failed(i32),
+    #[cfg(feature = "uring")]
+    Submitted(u64),
+}
+
+/// A write submitted by `DirectFile::write_async`, holding its buffer
+pub struct PendingWrite {
+    // Dropped once the write completed, the kernel may read from it until then
+    buf: ManuallyDrop<AlignedBuf>,
+    off: u64,
+    // Updated by `DirectFile::wait`
+    state: Mutex<WriteState>,
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        match *self.state.get_mut().unwrap() {
+            // Never waited, or the wait failed: leaked
+            #[cfg(feature = "uring")]
+            WriteState::Submitted(_) => {}
+            // Done or failed, the kernel is done with it
+            _ => unsafe { ManuallyDrop::drop(&mut self.buf) },
+        }
+    }
+}
+
+impl PendingWrite {
+    fn new(buf: AlignedBuf, off: u64, state: WriteState) -> Self {
+        PendingWrite {
+            buf: ManuallyDrop::new(buf),
+            off,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Offset of the write in the file
+    pub fn offset(&self) -> u64 {
+        self.off
+    }
+
+    /// Bytes being written
+    pub fn bytes(&self) -> &[u8] {
+        self.buf.as_slice()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -141,6 +407,70 @@ mod test {
         DirectFile::open(&path, Mode::Open, FileAccess::ReadWrite, 4096).unwrap()
     }
 
+    #[test]
+    fn read_write_test() {
+        let file = tmpfile("read_write");
+        let mut buf = AlignedBuf::new(8192);
+        for (i, byte) in buf.as_mut_slice().iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let pending = file.write_async(buf, 4096).unwrap();
+        assert_eq!(pending.bytes()[251], 0);
+        assert_eq!(file.wait(&pending).unwrap(), 8192);
+        // Its result is kept for waiting again
+        assert_eq!(file.wait(&pending).unwrap(), 8192);
+
+        // Waiters block in turn on a shared ring
+        let file = std::sync::Arc::new(file);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let file = std::sync::Arc::clone(&file);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        let reads = file.read_many(&[(4096, 10), (8192, 10)]).unwrap();
+                        assert_eq!(reads[1][0], (4096 % 251) as u8);
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let reads = file
+            .read_many(&[(4096, 10), (4096 + 4090, 20), (4096 + 300, 1)])
+            .unwrap();
+        let expected = |start: usize, len: usize| {
+            (start..start + len)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            reads,
+            [expected(0, 10), expected(4090, 20), expected(300, 1)]
+        );
+        assert_eq!(
+            file.read_at(4096 + 8190, 100).err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn span_test() {
+        let file = tmpfile("span");
+        let (start, buf) = file.span(4096 + 100, 10);
+        assert_eq!((start, buf.as_slice().len()), (4096, 4096));
+        // Ending on a block boundary needs no more block
+        let (start, buf) = file.span(4096, 8192);
+        assert_eq!((start, buf.as_slice().len()), (4096, 8192));
+
+        let path = tempdir().unwrap().into_path().join("span512");
+        let file = DirectFile::open(&path, Mode::Open, FileAccess::ReadWrite, 512).unwrap();
+        let (start, buf) = file.span(1000, 100);
+        assert_eq!((start, buf.as_slice().len()), (512, 1024));
+        assert_eq!(buf.as_slice().as_ptr() as usize % 4096, 0);
+    }
+
     #[test]
     fn simple() {
         let file = tmpfile("direct");
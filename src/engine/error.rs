@@ -1,6 +1,7 @@
 use std::convert::From;
 use std::fmt::{self, Debug, Display};
 use std::io;
+use std::path::PathBuf;
 
 pub enum Error {
     // For converting string to key
@@ -11,8 +12,24 @@ pub enum Error {
     OutOfIndex,
     // For build value from [u8]
     InvalidValueSize,
+    // For build key from [u8]
+    InvalidKeySize,
+    // For checking crc of a record
+    InvalidChecksum,
+    // For records failing the check in db files
+    Corrupted { file: PathBuf, offset: u64 },
     // For checking cache size when trying to read value
     CacheTooSmall,
+    // For writing once the ventries are used up, compaction renumbers them
+    LimitReached,
+    // For db files of another program, format or geometry
+    InvalidSuperblock { file: PathBuf, reason: String },
+    // For store options the files can not be laid out with
+    InvalidOptions { reason: String },
+    // For opening a store directory another store holds
+    Locked { file: PathBuf },
+    // For delta files that are not one, or do not follow the store they are applied to
+    InvalidDelta { file: PathBuf, reason: String },
     // For io error
     IoError(io::Error),
 }
@@ -36,7 +53,21 @@ impl Debug for Error {
             Error::WrongAlignment => write!(f, "Alignment error"),
             Error::OutOfIndex => write!(f, "Read out of index"),
             Error::InvalidValueSize => write!(f, "Invalid value size"),
+            Error::InvalidKeySize => write!(f, "Invalid key size"),
+            Error::InvalidChecksum => write!(f, "Invalid checksum"),
+            Error::Corrupted { file, offset } => {
+                write!(f, "Corrupted record in {:?} at {}", file, offset)
+            }
             Error::CacheTooSmall => write!(f, "Cache too small"),
+            Error::LimitReached => write!(f, "Limit of writes reached, compact the store"),
+            Error::InvalidSuperblock { file, reason } => {
+                write!(f, "Invalid superblock of {:?}: {}", file, reason)
+            }
+            Error::InvalidOptions { reason } => write!(f, "Invalid options: {}", reason),
+            Error::Locked { file } => write!(f, "Store locked by {:?}", file),
+            Error::InvalidDelta { file, reason } => {
+                write!(f, "Invalid delta {:?}: {}", file, reason)
+            }
             Error::IoError(err) => write!(f, "{:?}", err),
         }
     }
@@ -49,7 +80,21 @@ impl Display for Error {
             Error::WrongAlignment => write!(f, "Alignment error"),
             Error::OutOfIndex => write!(f, "Read out of index"),
             Error::InvalidValueSize => write!(f, "Invalid value size"),
+            Error::InvalidKeySize => write!(f, "Invalid key size"),
+            Error::InvalidChecksum => write!(f, "Invalid checksum"),
+            Error::Corrupted { file, offset } => {
+                write!(f, "Corrupted record in {:?} at {}", file, offset)
+            }
             Error::CacheTooSmall => write!(f, "Cache too small"),
+            Error::LimitReached => write!(f, "Limit of writes reached, compact the store"),
+            Error::InvalidSuperblock { file, reason } => {
+                write!(f, "Invalid superblock of {:?}: {}", file, reason)
+            }
+            Error::InvalidOptions { reason } => write!(f, "Invalid options: {}", reason),
+            Error::Locked { file } => write!(f, "Store locked by {:?}", file),
+            Error::InvalidDelta { file, reason } => {
+                write!(f, "Invalid delta {:?}: {}", file, reason)
+            }
             Error::IoError(err) => write!(f, "{}", err),
         }
     }
@@ -62,7 +107,30 @@ impl From<Error> for io::Error {
             Error::WrongAlignment => io::Error::new(io::ErrorKind::Other, "Alignment error"),
             Error::OutOfIndex => io::Error::new(io::ErrorKind::Other, "Read out of index"),
             Error::CacheTooSmall => io::Error::new(io::ErrorKind::Other, "Cache too small"),
+            Error::LimitReached => io::Error::other("Limit of writes reached, compact the store"),
             Error::InvalidValueSize => io::Error::new(io::ErrorKind::Other, "Invalid value size"),
+            Error::InvalidKeySize => io::Error::other("Invalid key size"),
+            Error::InvalidChecksum => io::Error::other("Invalid checksum"),
+            Error::Corrupted { file, offset } => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Corrupted record in {:?} at {}", file, offset),
+            ),
+            Error::InvalidSuperblock { file, reason } => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid superblock of {:?}: {}", file, reason),
+            ),
+            Error::InvalidOptions { reason } => io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid options: {}", reason),
+            ),
+            Error::Locked { file } => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("Store locked by {:?}", file),
+            ),
+            Error::InvalidDelta { file, reason } => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid delta {:?}: {}", file, reason),
+            ),
             Error::IoError(err) => err,
         }
     }
@@ -0,0 +1,423 @@
+use super::error::Error;
+use super::kv::*;
+use super::options::StoreOptions;
+use super::superblock::{self, FileKind, SUPERBLOCK_SIZE};
+use super::util;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// How bad an issue found by `check` is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// Repaired or ignored when opening the store, no write is lost
+    Warning,
+    /// Writes are lost, or the store fails to open
+    Error,
+}
+
+/// An issue found in a db file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub file: PathBuf,
+    /// offset in the file(superblock included), `None` for the whole file
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.offset {
+            Some(offset) => write!(
+                f,
+                "{}: {:?} at {}: {}",
+                severity, self.file, offset, self.message
+            ),
+            None => write!(f, "{}: {:?}: {}", severity, self.file, self.message),
+        }
+    }
+}
+
+/// What `check` went through and the issues it found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// keys sections walked
+    pub sections: u64,
+    /// committed key records, tombstones included
+    pub keys: u64,
+    pub tombstones: u64,
+    /// bytes of the values of the key records
+    pub value_bytes: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .count()
+    }
+
+    fn push(&mut self, severity: Severity, file: &Path, offset: Option<u64>, message: String) {
+        self.issues.push(Issue {
+            severity,
+            file: file.to_path_buf(),
+            offset,
+            message,
+        });
+    }
+}
+
+/// Check the db files of a closed store, without changing them
+/// Key sections are walked the way `util::build_index` does, every committed
+/// key record is checked against the values file and the buffer, and its value record is read.
+/// Returns `Err` only when the files can not be read.
+pub fn check<P: AsRef<Path>>(key_file: P, value_file: P, buffer_file: P) -> Result<Report, Error> {
+    let (key_file, value_file, buffer_file) =
+        (key_file.as_ref(), value_file.as_ref(), buffer_file.as_ref());
+    let mut report = Report::default();
+
+    let leftovers = [
+        util::with_suffix(key_file, util::COMPACT_SUFFIX),
+        util::with_suffix(key_file, util::COMPACTED_SUFFIX),
+        util::with_suffix(value_file, util::COMPACT_SUFFIX),
+    ];
+    for leftover in leftovers.iter().filter(|path| path.exists()) {
+        report.push(
+            Severity::Warning,
+            leftover,
+            None,
+            "left by an interrupted compaction, finished or rolled back on open".to_owned(),
+        );
+    }
+
+    let options = match check_superblocks(key_file, value_file, buffer_file, &mut report)? {
+        Some(options) => options,
+        // Records can not be found without the geometry
+        None => return Ok(report),
+    };
+    let (section_size, buffer_size) = (options.section_size as u64, options.buffer_size as u64);
+
+    let buffer_len = util::get_file_size(buffer_file)?;
+    if buffer_len != SUPERBLOCK_SIZE + buffer_size {
+        report.push(
+            Severity::Error,
+            buffer_file,
+            None,
+            format!(
+                "{} bytes, expected a superblock and a buffer of {}",
+                buffer_len, buffer_size
+            ),
+        );
+    }
+    // The buffer is always flushed as a whole, see `Store::init`
+    let value_size = util::get_data_size(value_file)?;
+    let value_pos = value_size / buffer_size * buffer_size;
+    if value_size != value_pos {
+        report.push(
+            Severity::Warning,
+            value_file,
+            Some(SUPERBLOCK_SIZE + value_pos),
+            format!(
+                "ends {} bytes into a buffer, a flush was cut short",
+                value_size - value_pos
+            ),
+        );
+    }
+
+    let key_size = util::get_data_size(key_file)?;
+    if key_size == 0 {
+        report.push(
+            Severity::Warning,
+            key_file,
+            None,
+            "no keys section, one is added on open".to_owned(),
+        );
+    } else if key_size % section_size != 0 {
+        let truncated = key_size % section_size;
+        report.push(
+            Severity::Error,
+            key_file,
+            Some(SUPERBLOCK_SIZE + key_size - truncated),
+            format!("truncated section, {} of {} bytes", truncated, section_size),
+        );
+    }
+
+    let values = Values {
+        value_file,
+        buffer_file,
+        values: File::open(value_file)?,
+        buffer: File::open(buffer_file)?,
+        value_pos,
+        buffer_size,
+        max_value_size: options.max_value_size(),
+    };
+    let last_end = walk_keys(key_file, key_size, section_size, &values, &mut report)?;
+
+    // At most a buffer is flushed past the last record: the one a new record did not fit in
+    if value_pos > last_end + buffer_size {
+        report.push(
+            Severity::Warning,
+            value_file,
+            Some(SUPERBLOCK_SIZE + last_end),
+            format!(
+                "{} bytes past the last value record, more than a buffer",
+                value_pos - last_end
+            ),
+        );
+    }
+
+    let index = util::with_suffix(key_file, util::INDEX_SUFFIX);
+    if index.exists() {
+        match util::load_index(&index)? {
+            None => report.push(
+                Severity::Warning,
+                &index,
+                None,
+                "broken index checkpoint, the index is rebuilt on open".to_owned(),
+            ),
+            Some(checkpoint) if checkpoint.key_end > key_size => report.push(
+                Severity::Warning,
+                &index,
+                None,
+                format!(
+                    "checkpoint up to {} past the keys file, ignored on open",
+                    checkpoint.key_end
+                ),
+            ),
+            Some(_) => {}
+        }
+    }
+    Ok(report)
+}
+
+/// Check the superblocks against the one of the keys file
+/// Returns the geometry they were written with, `None` if it is unknown
+fn check_superblocks(
+    key_file: &Path,
+    value_file: &Path,
+    buffer_file: &Path,
+    report: &mut Report,
+) -> Result<Option<StoreOptions>, Error> {
+    let mut invalid = |result: Result<_, Error>| match result {
+        Ok(superblock) => Ok(Some(superblock)),
+        Err(Error::InvalidSuperblock { file, reason }) => {
+            report.push(Severity::Error, &file, Some(0), reason);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    };
+    let key_superblock = match invalid(superblock::read(key_file))? {
+        Some(superblock) => superblock,
+        None => return Ok(None),
+    };
+    let options = StoreOptions {
+        section_size: key_superblock.section_size as usize,
+        buffer_size: key_superblock.buffer_size as usize,
+        ..StoreOptions::default()
+    };
+    if let Err(Error::InvalidOptions { reason }) = options.validate() {
+        report.push(Severity::Error, key_file, Some(0), reason);
+        return Ok(None);
+    }
+    let files = [
+        (key_file, FileKind::Keys),
+        (value_file, FileKind::Values),
+        (buffer_file, FileKind::Buffer),
+    ];
+    let mut valid = true;
+    for (path, kind) in files.iter() {
+        valid &= invalid(superblock::check(path, *kind, &options))?.is_some();
+    }
+    Ok(if valid { Some(options) } else { None })
+}
+
+/// Where the value records are read from
+struct Values<'a> {
+    value_file: &'a Path,
+    buffer_file: &'a Path,
+    values: File,
+    buffer: File,
+    // Start of the buffer, after the last whole buffer of the values file
+    value_pos: u64,
+    buffer_size: u64,
+    max_value_size: usize,
+}
+
+/// Walk the key sections like `util::replay_keys`, checking the committed records
+/// Returns the end of the value record of the last one
+fn walk_keys(
+    key_file: &Path,
+    key_size: u64,
+    section_size: u64,
+    values: &Values,
+    report: &mut Report,
+) -> Result<u64, Error> {
+    let mut reader = BufReader::new(File::open(key_file)?);
+    // Records of a batch not committed yet, with their offsets
+    let mut batch: Vec<(Key, u64)> = Vec::new();
+    let mut last: Option<Key> = None;
+    for pos in (0..key_size).step_by(section_size as usize) {
+        // A truncated section is walked as if padded with zeros
+        let mut section = vec![0; section_size as usize];
+        reader.seek(SeekFrom::Start(SUPERBLOCK_SIZE + pos))?;
+        (&mut reader)
+            .take(section_size)
+            .read_exact(&mut section[..(key_size - pos).min(section_size) as usize])?;
+        report.sections += 1;
+        let mut x = 0;
+        loop {
+            let offset = SUPERBLOCK_SIZE + pos + x as u64;
+            let key = match key_from_bytes(&section[x..]) {
+                Ok(Some(key)) => key,
+                Ok(None) => {
+                    if section[x..].iter().any(|byte| *byte != 0) {
+                        report.push(
+                            Severity::Warning,
+                            key_file,
+                            Some(offset),
+                            "stray bytes after the last record of the section".to_owned(),
+                        );
+                    }
+                    break;
+                }
+                Err(e) => {
+                    // The records after it can not be found
+                    report.push(
+                        Severity::Error,
+                        key_file,
+                        Some(offset),
+                        format!(
+                            "corrupted key record ({}), the rest of the section is lost",
+                            e
+                        ),
+                    );
+                    break;
+                }
+            };
+            x += key_record_size(key.inner.raw.len());
+            if key.flags & FLAG_BATCH == 0 {
+                discard(&mut batch, key_file, report);
+                check_key(&key, offset, last.as_ref(), key_file, values, report)?;
+                last = Some(key);
+                continue;
+            }
+            match batch.last() {
+                Some((prev, _)) if key.ventry <= prev.ventry => {
+                    discard(&mut batch, key_file, report)
+                }
+                _ => {}
+            }
+            let end = key.flags & FLAG_BATCH_END != 0;
+            batch.push((key, offset));
+            if end {
+                for (key, offset) in batch.drain(..) {
+                    check_key(&key, offset, last.as_ref(), key_file, values, report)?;
+                    last = Some(key);
+                }
+            }
+        }
+    }
+    discard(&mut batch, key_file, report);
+    Ok(last.map_or(0, |key| key.vend()))
+}
+
+/// Report the records of a batch never committed, dropped on open
+fn discard(batch: &mut Vec<(Key, u64)>, key_file: &Path, report: &mut Report) {
+    if let Some((_, offset)) = batch.first() {
+        report.push(
+            Severity::Warning,
+            key_file,
+            Some(*offset),
+            format!("batch of {} records never committed", batch.len()),
+        );
+    }
+    batch.clear();
+}
+
+/// Check a committed key record, following `last`, and read its value record
+fn check_key(
+    key: &Key,
+    offset: u64,
+    last: Option<&Key>,
+    key_file: &Path,
+    values: &Values,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let mut error = |message: String| report.push(Severity::Error, key_file, Some(offset), message);
+    if key.ventry > MAX_VENTRY {
+        error(format!("ventry {} out of range", key.ventry));
+    }
+    if let Some(last) = last.filter(|last| key.ventry <= last.ventry) {
+        error(format!(
+            "ventry {} after ventry {}",
+            key.ventry, last.ventry
+        ));
+    }
+    if key.vlen != TOMBSTONE && key.vlen as usize > values.max_value_size {
+        error(format!(
+            "value of {} bytes larger than the buffer",
+            key.vlen
+        ));
+        return Ok(());
+    }
+    let vend = key.vend();
+    let (file, handle, pos) = if vend <= values.value_pos {
+        (values.value_file, &values.values, key.vptr)
+    } else if key.vptr >= values.value_pos && vend <= values.value_pos + values.buffer_size {
+        (
+            values.buffer_file,
+            &values.buffer,
+            key.vptr - values.value_pos,
+        )
+    } else {
+        error(format!(
+            "value pointer {}..{} out of the values file({} bytes) and the buffer",
+            key.vptr, vend, values.value_pos
+        ));
+        return Ok(());
+    };
+
+    let mut record = vec![0; value_record_size(key.vlen)];
+    let message = match handle.read_exact_at(&mut record, SUPERBLOCK_SIZE + pos) {
+        Ok(()) => value_from_bytes(&record).err().map(|e| {
+            format!(
+                "corrupted value record ({}) of the key record at {}",
+                e, offset
+            )
+        }),
+        // A file shorter than its superblock tells, ie the buffer
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Some(format!(
+            "value record of the key record at {} past the end of the file",
+            offset
+        )),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(message) = message {
+        report.push(Severity::Error, file, Some(SUPERBLOCK_SIZE + pos), message);
+    }
+    report.keys += 1;
+    if key.vlen == TOMBSTONE {
+        report.tombstones += 1;
+    } else {
+        report.value_bytes += u64::from(key.vlen);
+    }
+    Ok(())
+}
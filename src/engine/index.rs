@@ -0,0 +1,195 @@
+use super::kv::*;
+use super::util::{find_insert_point, seek, seek_back};
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// In memory index of the keys file
+/// Keeps every version of a key, ordered by ventry, for snapshots to read.
+pub trait Index: Send + Sync {
+    /// Build from keys sorted by (inner, ventry)
+    fn from_sorted(keys: Vec<Key>) -> Self
+    where
+        Self: Sized;
+
+    /// Add a version, newer than the ones indexed
+    fn insert(&mut self, key: Key);
+
+    /// Versions of a key, ordered by ventry
+    fn versions(&self, inner: &InnerKey) -> Option<&[Key]>;
+
+    /// Versions of each key within the bounds, ordered by key
+    fn range<'a>(
+        &'a self,
+        start: Bound<&InnerKey>,
+        end: Bound<&InnerKey>,
+    ) -> Box<dyn DoubleEndedIterator<Item = &'a [Key]> + 'a>;
+}
+
+/// Build an `Index` of type `I`, for `Store` to rebuild it after compaction
+pub fn boxed<I: Index + 'static>(keys: Vec<Key>) -> Box<dyn Index> {
+    Box::new(I::from_sorted(keys))
+}
+
+/// Whether no key could be within the bounds
+fn is_empty_range(start: Bound<&InnerKey>, end: Bound<&InnerKey>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// Keys in a sorted vector
+/// Inserting shifts the vector, prefer `BTreeIndex` for random writes.
+#[derive(Default)]
+pub struct VecIndex {
+    keys: Vec<Key>,
+}
+
+impl Index for VecIndex {
+    fn from_sorted(keys: Vec<Key>) -> Self {
+        VecIndex { keys }
+    }
+
+    fn insert(&mut self, key: Key) {
+        let (_found, pos) = find_insert_point(&self.keys, &key.inner);
+        if pos == self.keys.len() {
+            self.keys.push(key);
+        } else {
+            self.keys.insert(pos, key);
+        }
+    }
+
+    fn versions(&self, inner: &InnerKey) -> Option<&[Key]> {
+        let start = seek(&self.keys, Bound::Included(inner));
+        let end = seek_back(&self.keys, Bound::Included(inner));
+        if start < end {
+            Some(&self.keys[start..end])
+        } else {
+            None
+        }
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&InnerKey>,
+        end: Bound<&InnerKey>,
+    ) -> Box<dyn DoubleEndedIterator<Item = &'a [Key]> + 'a> {
+        if is_empty_range(start, end) {
+            return Box::new(std::iter::empty());
+        }
+        let start = seek(&self.keys, start);
+        let end = seek_back(&self.keys, end);
+        Box::new(self.keys[start..end].chunk_by(|a, b| a.inner == b.inner))
+    }
+}
+
+/// Keys in a B-tree, each with its versions
+#[derive(Default)]
+pub struct BTreeIndex {
+    keys: BTreeMap<InnerKey, Vec<Key>>,
+}
+
+impl Index for BTreeIndex {
+    fn from_sorted(keys: Vec<Key>) -> Self {
+        let mut index = BTreeIndex::default();
+        for key in keys {
+            index.insert(key);
+        }
+        index
+    }
+
+    fn insert(&mut self, key: Key) {
+        self.keys.entry(key.inner.clone()).or_default().push(key);
+    }
+
+    fn versions(&self, inner: &InnerKey) -> Option<&[Key]> {
+        self.keys.get(inner).map(Vec::as_slice)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&InnerKey>,
+        end: Bound<&InnerKey>,
+    ) -> Box<dyn DoubleEndedIterator<Item = &'a [Key]> + 'a> {
+        // `BTreeMap::range` panics on those
+        if is_empty_range(start, end) {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(
+            self.keys
+                .range::<InnerKey, _>((start, end))
+                .map(|(_, versions)| versions.as_slice()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<Key> {
+        // (key, ventry) sorted by key then ventry
+        [("a", 1), ("a", 3), ("b", 0), ("c", 2), ("c", 4)]
+            .iter()
+            .map(|(raw, ventry)| Key {
+                inner: InnerKey::from(raw.as_bytes()),
+                ventry: *ventry,
+                vptr: 0,
+                vlen: 1,
+                flags: 0,
+                expires: 0,
+            })
+            .collect()
+    }
+
+    fn check<I: Index>() {
+        let mut index = I::from_sorted(keys());
+        index.insert(Key {
+            ventry: 5,
+            ..keys()[2].clone()
+        });
+
+        let ventries = |versions: &[Key]| versions.iter().map(|k| k.ventry).collect::<Vec<_>>();
+        let b = InnerKey::from(&b"b"[..]);
+        assert_eq!(ventries(index.versions(&b).unwrap()), [0, 5]);
+        assert!(index.versions(&InnerKey::from(&b"d"[..])).is_none());
+
+        let all: Vec<Vec<u64>> = index
+            .range(Bound::Unbounded, Bound::Unbounded)
+            .map(ventries)
+            .collect();
+        assert_eq!(all, [vec![1, 3], vec![0, 5], vec![2, 4]]);
+        let rev: Vec<Vec<u64>> = index
+            .range(Bound::Excluded(&b), Bound::Unbounded)
+            .rev()
+            .map(ventries)
+            .collect();
+        assert_eq!(rev, [vec![2, 4]]);
+        assert_eq!(
+            index
+                .range(Bound::Excluded(&b), Bound::Excluded(&b))
+                .count(),
+            0
+        );
+        assert_eq!(
+            index
+                .range(Bound::Included(&b), Bound::Included(&b))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn vec_index_test() {
+        check::<VecIndex>();
+    }
+
+    #[test]
+    fn btree_index_test() {
+        check::<BTreeIndex>();
+    }
+}
@@ -1,63 +1,74 @@
 use std::cmp::Ordering;
+use std::fmt;
 use std::str::FromStr;
-use std::string::ToString;
+
+use byteorder::{BigEndian, ByteOrder};
 
 use super::error::Error;
 
-/// key: 8 bytes
-pub const KEY_SIZE: usize = 8;
-/// 8 bytes for key, 4 bytes for the index of values
-pub const MKEY_SIZE: usize = 12;
-// each chunk max size
-pub const MAX_KV_PAIR: usize = 65536;
-/// size of keys which would be mem mapped
-pub const KEY_FILE_SIZE: usize = MAX_KV_PAIR * MKEY_SIZE;
-
-/// value: 256 bytes
-pub const VALUE_SIZE: usize = 256;
-/// size of each value block
-pub const VALUE_FILE_SIZE: usize = MAX_KV_PAIR * VALUE_SIZE;
-
-/// 16mb buffer size (mem mapped)
+/// key: at most 1kb
+pub const MAX_KEY_SIZE: usize = 1024;
+/// key record header: crc(4 bytes) + flags(1 byte) + key length(2 bytes)
+/// + value length(4 bytes) + ventry(8 bytes) + value pointer(8 bytes)
+/// + expiry(8 bytes)
+pub const KEY_HEADER_SIZE: usize = 35;
+/// largest ventry of a record, `u64::MAX` is newer than any of them
+pub const MAX_VENTRY: u64 = u64::MAX - 1;
+/// default size of each keys section, the last one would be mem mapped
+/// See `StoreOptions::section_size`
+pub const KEY_FILE_SIZE: usize = 1024 * 1024;
+
+/// value record header: crc(4 bytes) + value length(4 bytes)
+pub const VALUE_HEADER_SIZE: usize = 8;
+/// value: a whole record must fit in the buffer of the default size
+/// See `StoreOptions::max_value_size`
+pub const MAX_VALUE_SIZE: usize = BUFFER_SIZE - VALUE_HEADER_SIZE;
+/// value length marking a deleted key, larger than any value(see `StoreOptions::validate`)
+pub const TOMBSTONE: u32 = 0xffff_ffff;
+
+/// key record flag: written by a batch
+pub const FLAG_BATCH: u8 = 0x01;
+/// key record flag: the last record of a batch, commits the batch
+pub const FLAG_BATCH_END: u8 = 0x02;
+/// key record flag: set on every record, its ventry takes 8 bytes
+pub const FLAG_VENTRY64: u8 = 0x04;
+
+/// number of writes between two index checkpoints
+pub const INDEX_CHECKPOINT_INTERVAL: u64 = 1 << 20;
+/// number of keys an index checkpoint reads at a time under the index lock
+pub const INDEX_CHECKPOINT_CHUNK: usize = 4096;
+
+/// 16mb buffer size (mem mapped) by default
+/// See `StoreOptions::buffer_size`
 pub const BUFFER_SIZE: usize = 16 * 1024 * 1024;
 
-/// Keys are only allowed in 8 bytes
-/// While Values for 256 bytes each
-/// See README.md#limitation
-pub type KeyRaw = [u8; 8];
-pub type ValueRaw = [u8; 256];
+/// Layout of the stores written before superblocks, see `util::migrate_baseline`:
+/// key record: key(8 bytes, zero padded) + ventry(4 bytes)
+pub const BASELINE_KEY_SIZE: usize = 12;
+/// keys section: 65536 key records
+pub const BASELINE_KEY_FILE_SIZE: usize = 65536 * BASELINE_KEY_SIZE;
+/// value slot of ventry `n` at `n * 256`: value(zero padded), all 0xff if deleted
+pub const BASELINE_VALUE_SIZE: usize = 256;
+/// buffer file: the slots not flushed to the values file yet
+pub const BASELINE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
 
+/// Keys are variable length byte strings
+/// See README.md#limitation
 #[derive(Debug, Clone)]
 pub struct InnerKey {
-    pub raw: KeyRaw,
+    pub raw: Vec<u8>,
 }
 
-#[derive(Clone)]
-pub struct InnerValue {
-    pub raw: ValueRaw,
-}
-
-impl ToString for InnerKey {
-    fn to_string(&self) -> String {
-        self.raw
-            .iter()
-            .cloned()
-            .map(|x| x as char)
-            .collect::<String>()
-            .trim_matches(char::from(0))
-            .to_owned()
+impl From<&[u8]> for InnerKey {
+    fn from(raw: &[u8]) -> Self {
+        InnerKey { raw: raw.to_vec() }
     }
 }
 
-impl ToString for InnerValue {
-    fn to_string(&self) -> String {
-        self.raw
-            .iter()
-            .cloned()
-            .map(|x| x as char)
-            .collect::<String>()
-            .trim_matches(char::from(0))
-            .to_owned()
+/// Keys are shown as utf8 text, every byte kept(NULs included)
+impl fmt::Display for InnerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.raw))
     }
 }
 
@@ -65,77 +76,169 @@ impl FromStr for InnerKey {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() > 8 {
-            return Err(Error::ContentExceed);
-        }
-        let mut key = [0; 8];
-        let chars = s.as_bytes();
-        key[..s.len()].clone_from_slice(&chars[..s.len()]);
-        Ok(InnerKey { raw: key })
-    }
-}
-
-impl FromStr for InnerValue {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() > 256 {
+        if s.len() > MAX_KEY_SIZE {
             return Err(Error::ContentExceed);
         }
-        let mut value = [0; 256];
-        let chars = s.as_bytes();
-        value[..s.len()].clone_from_slice(&chars[..s.len()]);
-        Ok(InnerValue { raw: value })
+        Ok(InnerKey::from(s.as_bytes()))
     }
 }
 
+/// Position of a key's value in the values log
 #[derive(Debug, Clone)]
 pub struct Key {
     pub inner: InnerKey,
-    pub ventry: usize,
+    pub ventry: u64,
+    /// offset of the value record
+    pub vptr: u64,
+    /// length of the value, `TOMBSTONE` if deleted
+    pub vlen: u32,
+    /// `FLAG_*` of the record
+    pub flags: u8,
+    /// unix time in milliseconds the key expires at, `0` for never
+    pub expires: u64,
+}
+
+impl Key {
+    /// Offset right after the value record
+    pub fn vend(&self) -> u64 {
+        self.vptr + value_record_size(self.vlen) as u64
+    }
+
+    /// Whether the key has expired at `now`(unix time in milliseconds)
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
 }
 
 pub enum Value {
-    Valid(Box<InnerValue>),
+    Valid(Vec<u8>),
     Invalid,
 }
 
-pub fn value_to_bytes(value: &Value) -> &[u8] {
-    match value {
-        Value::Invalid => &[255u8; VALUE_SIZE],
-        Value::Valid(v) => &v.raw,
+impl Value {
+    pub fn vlen(&self) -> u32 {
+        match self {
+            Value::Invalid => TOMBSTONE,
+            Value::Valid(v) => v.len() as u32,
+        }
+    }
+}
+
+/// Size of a value record on disk
+pub fn value_record_size(vlen: u32) -> usize {
+    if vlen == TOMBSTONE {
+        VALUE_HEADER_SIZE
+    } else {
+        VALUE_HEADER_SIZE + vlen as usize
     }
 }
 
+/// Size of a key record on disk
+pub fn key_record_size(klen: usize) -> usize {
+    KEY_HEADER_SIZE + klen
+}
+
+/// Value record: [crc(4 bytes)][length(4 bytes)][value]
+/// crc covers the rest of the record
+pub fn value_to_bytes(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![0u8; value_record_size(value.vlen())];
+    BigEndian::write_u32(&mut bytes[4..VALUE_HEADER_SIZE], value.vlen());
+    if let Value::Valid(v) = value {
+        bytes[VALUE_HEADER_SIZE..].clone_from_slice(v);
+    }
+    let crc = crc32fast::hash(&bytes[4..]);
+    BigEndian::write_u32(&mut bytes[0..4], crc);
+    bytes
+}
+
 pub fn value_from_bytes(bytes: &[u8]) -> Result<Value, Error> {
-    if bytes.len() != VALUE_SIZE {
+    if bytes.len() < VALUE_HEADER_SIZE {
         return Err(Error::InvalidValueSize);
     }
-    if bytes[..] == [255u8; VALUE_SIZE][..] {
+    let vlen = BigEndian::read_u32(&bytes[4..VALUE_HEADER_SIZE]);
+    if bytes.len() != value_record_size(vlen) {
+        return Err(Error::InvalidValueSize);
+    }
+    if BigEndian::read_u32(&bytes[0..4]) != crc32fast::hash(&bytes[4..]) {
+        return Err(Error::InvalidChecksum);
+    }
+    if vlen == TOMBSTONE {
         return Ok(Value::Invalid);
     }
-    let mut inner = InnerValue {
-        raw: [0; VALUE_SIZE],
-    };
-    inner.raw.clone_from_slice(bytes);
-    Ok(Value::Valid(Box::new(inner)))
+    Ok(Value::Valid(bytes[VALUE_HEADER_SIZE..].to_vec()))
 }
 
+/// Key record: [crc(4 bytes)][flags(1 byte)][key length(2 bytes)][value length(4 bytes)][ventry(8 bytes)][value pointer(8 bytes)][expiry(8 bytes)][key]
+/// crc covers the rest of the record
 pub fn key_to_bytes(key: &Key) -> Vec<u8> {
-    let mut bytes = vec![0u8; MKEY_SIZE];
-    bytes[..KEY_SIZE].clone_from_slice(&key.inner.raw[..KEY_SIZE]);
-    let ventry = key.ventry;
-    bytes[KEY_SIZE] = (ventry >> 24) as u8;
-    bytes[KEY_SIZE + 1] = (ventry >> 16) as u8;
-    bytes[KEY_SIZE + 2] = (ventry >> 8) as u8;
-    bytes[KEY_SIZE + 3] = ventry as u8;
+    let klen = key.inner.raw.len();
+    let mut bytes = vec![0u8; key_record_size(klen)];
+    bytes[4] = key.flags | FLAG_VENTRY64;
+    BigEndian::write_u16(&mut bytes[5..7], klen as u16);
+    BigEndian::write_u32(&mut bytes[7..11], key.vlen);
+    BigEndian::write_u64(&mut bytes[11..19], key.ventry);
+    BigEndian::write_u64(&mut bytes[19..27], key.vptr);
+    BigEndian::write_u64(&mut bytes[27..KEY_HEADER_SIZE], key.expires);
+    bytes[KEY_HEADER_SIZE..].clone_from_slice(&key.inner.raw);
+    let crc = crc32fast::hash(&bytes[4..]);
+    BigEndian::write_u32(&mut bytes[0..4], crc);
 
     bytes
 }
 
+/// Parse a key record from the head of `bytes`
+/// Returns `None` when reaching the end of a section
+pub fn key_from_bytes(bytes: &[u8]) -> Result<Option<Key>, Error> {
+    if bytes.len() < KEY_HEADER_SIZE {
+        return Ok(None);
+    }
+    let klen = BigEndian::read_u16(&bytes[5..7]) as usize;
+    if klen == 0 {
+        return Ok(None);
+    }
+    if klen > MAX_KEY_SIZE || bytes.len() < key_record_size(klen) {
+        return Err(Error::InvalidKeySize);
+    }
+    let record = &bytes[..key_record_size(klen)];
+    if BigEndian::read_u32(&record[0..4]) != crc32fast::hash(&record[4..]) {
+        return Err(Error::InvalidChecksum);
+    }
+    Ok(Some(Key {
+        inner: InnerKey::from(&record[KEY_HEADER_SIZE..]),
+        vlen: BigEndian::read_u32(&record[7..11]),
+        ventry: BigEndian::read_u64(&record[11..19]),
+        vptr: BigEndian::read_u64(&record[19..27]),
+        flags: record[4] & !FLAG_VENTRY64,
+        expires: BigEndian::read_u64(&record[27..KEY_HEADER_SIZE]),
+    }))
+}
+
+/// Parse a key record of a store written before superblocks from the head of `bytes`
+/// [key(8 bytes, zero padded)][ventry(4 bytes)]
+/// Returns the key and its ventry, `None` when reaching the end of a section
+pub fn baseline_key_from_bytes(bytes: &[u8]) -> Option<(InnerKey, u64)> {
+    if bytes.len() < BASELINE_KEY_SIZE || bytes[..BASELINE_KEY_SIZE] == [0; BASELINE_KEY_SIZE] {
+        return None;
+    }
+    let raw = &bytes[..8];
+    let len = raw.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let ventry = u64::from(BigEndian::read_u32(&bytes[8..BASELINE_KEY_SIZE]));
+    Some((InnerKey::from(&raw[..len]), ventry))
+}
+
+/// Parse a value slot of a store written before superblocks
+/// Values were zero padded, so trailing zeros are not part of them.
+pub fn baseline_value_from_bytes(bytes: &[u8]) -> Value {
+    if bytes.iter().all(|b| *b == 0xff) {
+        return Value::Invalid;
+    }
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    Value::Valid(bytes[..len].to_vec())
+}
+
 impl PartialOrd for InnerKey {
     fn partial_cmp(&self, other: &InnerKey) -> Option<Ordering> {
-        Some(self.raw.cmp(&other.raw))
+        Some(self.cmp(other))
     }
 }
 
@@ -144,3 +247,11 @@ impl PartialEq for InnerKey {
         self.raw == other.raw
     }
 }
+
+impl Eq for InnerKey {}
+
+impl Ord for InnerKey {
+    fn cmp(&self, other: &InnerKey) -> Ordering {
+        self.raw.cmp(&other.raw)
+    }
+}
@@ -1,5 +1,16 @@
+pub mod batch;
+pub mod cache;
+pub mod delta;
 pub mod dio;
 pub mod error;
+pub mod fsck;
+pub mod index;
 pub mod kv;
+pub mod options;
+pub mod shard;
 pub mod store;
+pub mod superblock;
 pub mod util;
+
+#[cfg(feature = "uring")]
+pub mod uring;
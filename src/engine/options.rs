@@ -0,0 +1,225 @@
+use super::error::Error;
+use super::index::{self, BTreeIndex, Index};
+use super::kv::{
+    key_record_size, Key, BUFFER_SIZE, KEY_FILE_SIZE, MAX_KEY_SIZE, VALUE_HEADER_SIZE,
+};
+use super::store::Store;
+use super::superblock::SUPERBLOCK_SIZE;
+use super::util;
+
+use std::path::Path;
+
+/// 8mb of value blocks cached by default
+pub const DEFAULT_CACHE_SIZE: usize = 8 * 1024 * 1024;
+/// Direct io alignment of the values file by default
+pub const DEFAULT_ALIGNMENT: usize = 4096;
+/// Keys file name of a store opened by `StoreBuilder::open`
+pub const DEFAULT_KEY_FILE: &str = "toy.k";
+/// Values file name of a store opened by `StoreBuilder::open`
+pub const DEFAULT_VALUE_FILE: &str = "toy.v";
+/// Buffer file name of a store opened by `StoreBuilder::open`
+pub const DEFAULT_BUFFER_FILE: &str = "toy.b";
+
+/// Buffers are copied into 4k blocks for direct io
+const BLOCK: usize = 4096;
+
+/// When writes are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS, writes may be lost on a crash
+    Never,
+    /// Sync the values file each time the buffer is written to it
+    Flush,
+    /// Also sync the mapped keys and buffer after every write
+    Always,
+}
+
+/// Tunables of a `Store`
+/// `buffer_size` and `section_size` only apply to a new store,
+/// an existing one keeps those of its superblocks.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreOptions {
+    /// bytes of value blocks kept in the block cache
+    pub cache_size: usize,
+    /// builds the index from the sorted keys, ie `index::boxed::<VecIndex>`
+    pub index: fn(Vec<Key>) -> Box<dyn Index>,
+    /// size of the buffer, the values file grows by it and a value record must fit in it
+    pub buffer_size: usize,
+    /// size of each keys section, the last one is mem mapped
+    pub section_size: usize,
+    /// alignment of direct io on the values file
+    pub alignment: usize,
+    pub sync: SyncPolicy,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            cache_size: DEFAULT_CACHE_SIZE,
+            index: index::boxed::<BTreeIndex>,
+            buffer_size: BUFFER_SIZE,
+            section_size: KEY_FILE_SIZE,
+            alignment: DEFAULT_ALIGNMENT,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+impl StoreOptions {
+    /// Largest value a record in the buffer can hold
+    pub fn max_value_size(&self) -> usize {
+        self.buffer_size - VALUE_HEADER_SIZE
+    }
+
+    /// Check that the store can be laid out with these options
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: String| Err(Error::InvalidOptions { reason });
+        if !self.alignment.is_power_of_two() || self.alignment > SUPERBLOCK_SIZE as usize {
+            return invalid(format!(
+                "alignment {} is not a power of two up to {}",
+                self.alignment, SUPERBLOCK_SIZE
+            ));
+        }
+        if self.buffer_size <= VALUE_HEADER_SIZE
+            || !self.buffer_size.is_multiple_of(BLOCK)
+            || self.buffer_size > u32::MAX as usize
+        {
+            return invalid(format!(
+                "buffer size {} is not a multiple of {} below 4gb",
+                self.buffer_size, BLOCK
+            ));
+        }
+        // Sections are mapped one at a time
+        let page = BLOCK.max(util::page_size());
+        if self.section_size < key_record_size(MAX_KEY_SIZE)
+            || !self.section_size.is_multiple_of(page)
+        {
+            return invalid(format!(
+                "section size {} is not a multiple of {} holding a {} bytes key",
+                self.section_size, page, MAX_KEY_SIZE
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Opens a store under a directory, ie `StoreBuilder::new().buffer_size(4 << 20).open(dir)`
+pub struct StoreBuilder {
+    options: StoreOptions,
+    key_file: String,
+    value_file: String,
+    buffer_file: String,
+}
+
+impl Default for StoreBuilder {
+    fn default() -> Self {
+        StoreBuilder::new()
+    }
+}
+
+impl StoreBuilder {
+    pub fn new() -> Self {
+        StoreBuilder {
+            options: StoreOptions::default(),
+            key_file: DEFAULT_KEY_FILE.to_owned(),
+            value_file: DEFAULT_VALUE_FILE.to_owned(),
+            buffer_file: DEFAULT_BUFFER_FILE.to_owned(),
+        }
+    }
+
+    pub fn cache_size(mut self, size: usize) -> Self {
+        self.options.cache_size = size;
+        self
+    }
+
+    pub fn index<I: Index + 'static>(mut self) -> Self {
+        self.options.index = index::boxed::<I>;
+        self
+    }
+
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.options.buffer_size = size;
+        self
+    }
+
+    pub fn section_size(mut self, size: usize) -> Self {
+        self.options.section_size = size;
+        self
+    }
+
+    pub fn alignment(mut self, alignment: usize) -> Self {
+        self.options.alignment = alignment;
+        self
+    }
+
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    /// Names of the keys, values and buffer files in the directory
+    pub fn file_names(mut self, key_file: &str, value_file: &str, buffer_file: &str) -> Self {
+        self.key_file = key_file.to_owned();
+        self.value_file = value_file.to_owned();
+        self.buffer_file = buffer_file.to_owned();
+        self
+    }
+
+    pub fn options(&self) -> &StoreOptions {
+        &self.options
+    }
+
+    /// Open the store under `dir`, creating the directory if missing
+    /// The directory is locked until the store is dropped, see `util::lock_dir`.
+    pub fn open<P: AsRef<Path>>(self, dir: P) -> Result<Store, Error> {
+        std::fs::create_dir_all(&dir)?;
+        let lock = util::lock_dir(&dir)?;
+        let dir = dir.as_ref();
+        let store = Store::with_options(
+            dir.join(&self.key_file),
+            dir.join(&self.value_file),
+            dir.join(&self.buffer_file),
+            self.options,
+        )?;
+        Ok(store.with_lock(lock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_test() {
+        assert!(StoreOptions::default().validate().is_ok());
+        let cases = [
+            StoreOptions {
+                alignment: 1000,
+                ..StoreOptions::default()
+            },
+            StoreOptions {
+                alignment: 8192,
+                ..StoreOptions::default()
+            },
+            StoreOptions {
+                buffer_size: 4097,
+                ..StoreOptions::default()
+            },
+            StoreOptions {
+                section_size: 1024,
+                ..StoreOptions::default()
+            },
+        ];
+        for options in cases.iter() {
+            assert!(options.validate().is_err());
+        }
+        let small = StoreOptions {
+            alignment: 512,
+            buffer_size: 4096,
+            section_size: 4096.max(util::page_size()),
+            ..StoreOptions::default()
+        };
+        assert!(small.validate().is_ok());
+        assert_eq!(small.max_value_size(), 4096 - VALUE_HEADER_SIZE);
+    }
+}
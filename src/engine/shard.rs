@@ -0,0 +1,177 @@
+use super::error;
+use super::store::{Store, StoreIter};
+use super::util;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::thread;
+
+/// File of a sharded store directory holding its number of shards
+pub const SHARDS_FILE: &str = "SHARDS";
+
+/// Keys hashed across independent `Store`s under one directory
+/// Shard `i` owns `toy-{i}.k`, `toy-{i}.v` and `toy-{i}.b`.
+/// The number of shards is written to `SHARDS` when the directory is created,
+/// opening it with another number fails.
+pub struct ShardedStore {
+    shards: Vec<Store>,
+    // Lock of the directory, shared by the shards
+    _lock: File,
+}
+
+impl ShardedStore {
+    /// Open `shards` stores under `dir`, building their indexes in parallel
+    pub fn new<P: AsRef<Path>>(dir: P, shards: usize) -> Result<Self, error::Error> {
+        if shards == 0 {
+            return Err(error::Error::InvalidOptions {
+                reason: "a sharded store needs at least one shard".to_owned(),
+            });
+        }
+        fs::create_dir_all(&dir)?;
+        let lock = util::lock_dir(&dir)?;
+        check_shards(dir.as_ref(), shards)?;
+        let handles: Vec<_> = (0..shards)
+            .map(|i| {
+                let dir = dir.as_ref().to_path_buf();
+                thread::spawn(move || {
+                    Store::new(
+                        dir.join(format!("toy-{}.k", i)),
+                        dir.join(format!("toy-{}.v", i)),
+                        dir.join(format!("toy-{}.b", i)),
+                    )
+                })
+            })
+            .collect();
+        let shards = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("failed to open shard"))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ShardedStore {
+            shards,
+            _lock: lock,
+        })
+    }
+
+    /// Index of the shard owning `key`
+    fn shard_of(&self, key: &[u8]) -> usize {
+        crc32fast::hash(key) as usize % self.shards.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, error::Error> {
+        self.shards[self.shard_of(key)].get(key)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), error::Error> {
+        let shard = self.shard_of(key);
+        self.shards[shard].put(key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), error::Error> {
+        let shard = self.shard_of(key);
+        self.shards[shard].delete(key)
+    }
+
+    /// Scan keys of all shards in order
+    pub fn scan(&self) -> ShardIter<'_> {
+        ShardIter::new(self.shards.iter().map(Store::scan).collect())
+    }
+
+    /// Scan keys of all shards within `range` in order
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> ShardIter<'_> {
+        ShardIter::new(
+            self.shards
+                .iter()
+                .map(|shard| shard.range::<K, _>((range.start_bound(), range.end_bound())))
+                .collect(),
+        )
+    }
+
+    /// Compact each shard
+    pub fn compact(&mut self) -> Result<(), error::Error> {
+        for shard in self.shards.iter_mut() {
+            shard.compact()?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the number of shards the directory was created with, recording it for a new one
+/// Shard files without `SHARDS` are refused, the number they were created with is unknown.
+fn check_shards(dir: &Path, shards: usize) -> Result<(), error::Error> {
+    let path = dir.join(SHARDS_FILE);
+    let created = if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        content
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| error::Error::InvalidOptions {
+                reason: format!("{:?} holds {:?}, not a number of shards", path, content),
+            })?
+    } else {
+        if dir.join("toy-0.k").exists() {
+            return Err(error::Error::InvalidOptions {
+                reason: format!("{:?} holds shards but no {}", dir, SHARDS_FILE),
+            });
+        }
+        let tmp = util::with_suffix(&path, util::COMPACT_SUFFIX);
+        {
+            let mut f = File::create(&tmp)?;
+            writeln!(f, "{}", shards)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        util::sync_dir(&path)?;
+        shards
+    };
+    if created != shards {
+        return Err(error::Error::InvalidOptions {
+            reason: format!(
+                "{:?} was created with {} shards, opened with {}",
+                dir, created, shards
+            ),
+        });
+    }
+    Ok(())
+}
+
+type Item = Result<(Vec<u8>, Vec<u8>), error::Error>;
+
+/// Merges the ordered iterators of shards
+/// Shards hold disjoint keys, so the smallest head is always the next one
+pub struct ShardIter<'a> {
+    iters: Vec<StoreIter<'a>>,
+    // Next item of each iterator
+    heads: Vec<Option<Item>>,
+}
+
+impl<'a> ShardIter<'a> {
+    fn new(mut iters: Vec<StoreIter<'a>>) -> Self {
+        let heads = iters.iter_mut().map(Iterator::next).collect();
+        ShardIter { iters, heads }
+    }
+}
+
+impl<'a> Iterator for ShardIter<'a> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            match (head, next.map(|j| &self.heads[j])) {
+                (None, _) => continue,
+                // Report errors first
+                (Some(Err(_)), _) => {
+                    next = Some(i);
+                    break;
+                }
+                (Some(Ok((key, _))), Some(Some(Ok((min, _))))) if key >= min => continue,
+                _ => next = Some(i),
+            }
+        }
+        let i = next?;
+        let head = self.iters[i].next();
+        std::mem::replace(&mut self.heads[i], head)
+    }
+}
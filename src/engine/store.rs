@@ -1,54 +1,173 @@
-use super::dio::{Block4k, DirectFile, FileAccess, Mode};
+use super::batch::WriteBatch;
+use super::cache::{BlockCache, CacheStats, BLOCK_SIZE};
+use super::delta::{DeltaReader, DeltaWriter};
+use super::dio::{AlignedBuf, DirectFile, FileAccess, Mode, PendingWrite};
 use super::error;
+use super::index::{self, Index};
 use super::kv::*;
+use super::options::{StoreBuilder, StoreOptions, SyncPolicy, DEFAULT_CACHE_SIZE};
+use super::superblock::{self, FileKind, SUPERBLOCK_SIZE};
 use super::util::{self, *};
 
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter::Rev;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
-
-use memmap::MmapMut;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 
 /// Seperating keys and values
 /// Managing keys and index via km
 /// Managing values via vm
+/// Writes are serialized by `writer`, reads do not take it and run while a write appends.
 pub struct Store {
     km: KeyManager,
     vm: ValueManager,
     key_file: PathBuf,
     buffer_file: PathBuf,
     value_file: PathBuf,
+    // Kept for reopening after compaction
+    options: StoreOptions,
+    // ventry of the last index checkpoint
+    checkpointed: AtomicU64,
+    // Held by a write from its checks to indexing its keys
+    writer: Mutex<()>,
+    // Held while an index checkpoint is written, outside of `writer`
+    saving: Mutex<()>,
+    // ventries of the live snapshots, with how many are taken at each
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    // Lock of the directory, held while a store opened by `Store::open` lives
+    lock: Option<File>,
+}
+
+/// A point-in-time view of the store
+/// Versions written after it was taken (ventry >= its ventry) are invisible.
+/// It borrows the store, which can not be compacted(renumbering ventries) while it lives.
+pub struct Snapshot<'a> {
+    store: &'a Store,
+    ventry: u64,
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        let mut snapshots = self.store.snapshots.lock().unwrap();
+        if let Entry::Occupied(mut count) = snapshots.entry(self.ventry) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, error::Error> {
+        self.store.get_before(key, self.ventry)
+    }
+
+    pub fn scan(&self) -> StoreIter<'a> {
+        StoreIter::new(self.store).before(self.ventry)
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> StoreIter<'a> {
+        self.store.range(range).before(self.ventry)
+    }
 }
 
 /// For iteraing the store
+/// Yields keys within `[start, end]` of the index, from both ends
 pub struct StoreIter<'a> {
-    store: &'a mut Store,
-    index: usize,
+    store: &'a Store,
+    // Narrowed by each key yielded from the front
+    start: Bound<InnerKey>,
+    // Narrowed by each key yielded from the back
+    end: Bound<InnerKey>,
+    // Only versions with a smaller ventry are visible
+    watermark: u64,
+    // Keys expired at the time of the scan are skipped
+    now: u64,
 }
 
 impl<'a> StoreIter<'a> {
-    pub fn new(store: &'a mut Store) -> Self {
-        StoreIter { store, index: 0 }
+    pub fn new(store: &'a Store) -> Self {
+        StoreIter::with_range(store, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Iterate keys within the bounds only
+    pub fn with_range(store: &'a Store, start: Bound<&InnerKey>, end: Bound<&InnerKey>) -> Self {
+        StoreIter {
+            store,
+            start: start.cloned(),
+            end: end.cloned(),
+            watermark: u64::MAX,
+            now: util::now_millis(),
+        }
+    }
+
+    /// Ignore versions written since `ventry`
+    pub fn before(mut self, ventry: u64) -> Self {
+        self.watermark = ventry;
+        self
+    }
+
+    /// The latest visible version among `versions`
+    fn visible(&self, versions: &[Key]) -> Option<Key> {
+        versions
+            .iter()
+            .rev()
+            .find(|key| key.ventry < self.watermark)
+            .filter(|key| !key.is_expired(self.now))
+            .cloned()
+    }
+
+    fn read(&self, key: &Key) -> Option<<Self as Iterator>::Item> {
+        match self.store.vm.read(key) {
+            Ok(Value::Valid(v)) => Some(Ok((key.inner.raw.clone(), v))),
+            Ok(Value::Invalid) => None,
+            Err(e) => Some(Err(e)),
+        }
     }
 }
 
 impl<'a> Iterator for StoreIter<'a> {
-    type Item = (InnerKey, InnerValue);
+    type Item = Result<(Vec<u8>, Vec<u8>), error::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let rindex = self.store.km.index.read().unwrap();
-        while self.index < rindex.len() {
-            let key = &rindex[self.index];
-            if self.index + 1 < rindex.len() && key.inner == rindex[self.index + 1].inner {
-                self.index += 1;
-                continue;
+        loop {
+            let key = {
+                let rindex = self.store.km.index.read().unwrap();
+                let versions = rindex
+                    .range(self.start.as_ref(), self.end.as_ref())
+                    .next()?;
+                self.start = Bound::Excluded(versions[0].inner.clone());
+                self.visible(versions)
+            };
+            if let Some(item) = key.and_then(|key| self.read(&key)) {
+                return Some(item);
             }
-            let value = self.store.vm.read(key.ventry).unwrap();
-            self.index += 1;
-            if let Value::Valid(v) = value {
-                return Some((key.inner.clone(), *v.clone()));
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for StoreIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = {
+                let rindex = self.store.km.index.read().unwrap();
+                let versions = rindex
+                    .range(self.start.as_ref(), self.end.as_ref())
+                    .next_back()?;
+                self.end = Bound::Excluded(versions[0].inner.clone());
+                self.visible(versions)
+            };
+            if let Some(item) = key.and_then(|key| self.read(&key)) {
+                return Some(item);
             }
         }
-        None
     }
 }
 
@@ -58,60 +177,149 @@ impl Store {
         value_file: P,
         buffer_file: P,
     ) -> Result<Self, error::Error> {
+        Store::with_options(key_file, value_file, buffer_file, StoreOptions::default())
+    }
+
+    /// Open the store, indexing keys with `I`
+    pub fn with_index<I: Index + 'static, P: AsRef<Path>>(
+        key_file: P,
+        value_file: P,
+        buffer_file: P,
+    ) -> Result<Self, error::Error> {
+        let options = StoreOptions {
+            index: index::boxed::<I>,
+            ..StoreOptions::default()
+        };
+        Store::with_options(key_file, value_file, buffer_file, options)
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        key_file: P,
+        value_file: P,
+        buffer_file: P,
+        options: StoreOptions,
+    ) -> Result<Self, error::Error> {
+        options.validate()?;
+        // Finish the compaction if it crashed after commit
+        util::recover_compaction(&key_file, &value_file)?;
+        // Check the superblocks, files written before them are upgraded once.
+        // An existing store keeps the geometry it was created with.
+        let options = superblock::prepare(&key_file, &value_file, &buffer_file, &options)?;
         // Make sure the DB files have enough space
-        let key_pos = util::ensure_size(&key_file, KEY_FILE_SIZE as u64, MKEY_SIZE as u64)?;
-        util::ensure_size(&value_file, VALUE_FILE_SIZE as u64, VALUE_SIZE as u64)?;
-        // FIXME: Corner case should do a flush when the buffer is full here
-        let buffer_pos = util::ensure_size(&buffer_file, BUFFER_SIZE as u64, VALUE_SIZE as u64)?;
+        util::ensure_size(&key_file, SUPERBLOCK_SIZE + options.section_size as u64)?;
+        util::ensure_size(&buffer_file, SUPERBLOCK_SIZE + options.buffer_size as u64)?;
+        Store::init(&key_file, &value_file, &buffer_file, options)
+    }
 
-        // Compute the value file position
-        let value_pos =
-            (key_pos / MKEY_SIZE as u64 - buffer_pos / VALUE_SIZE as u64) * VALUE_SIZE as u64;
-        Store::init(&key_file, &value_file, &buffer_file, value_pos)
+    /// Open the store under `dir` with the default options,
+    /// creating it if missing. Fails with `Error::Locked` while another store holds it.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, error::Error> {
+        StoreBuilder::new().open(dir)
     }
 
-    fn ensure_size(&mut self) -> Result<(u64, u64, u64), error::Error> {
-        let key_pos = util::ensure_size(&self.key_file, KEY_FILE_SIZE as u64, MKEY_SIZE as u64)?;
-        util::ensure_size(&self.value_file, VALUE_FILE_SIZE as u64, VALUE_SIZE as u64)?;
-        let buffer_pos =
-            util::ensure_size(&self.buffer_file, BUFFER_SIZE as u64, VALUE_SIZE as u64)?;
+    /// Configure a store opened under a directory
+    pub fn builder() -> StoreBuilder {
+        StoreBuilder::new()
+    }
+
+    /// Hold the lock of the directory as long as the store lives
+    pub(crate) fn with_lock(mut self, lock: File) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// Options the store runs with, its geometry is the one it was created with
+    pub fn options(&self) -> &StoreOptions {
+        &self.options
+    }
 
-        let value_pos =
-            (key_pos / MKEY_SIZE as u64 - buffer_pos / VALUE_SIZE as u64) * VALUE_SIZE as u64;
+    /// Map a new section of keys once the current one is full
+    fn ensure_size(&self) -> Result<u64, error::Error> {
+        let section_size = self.options.section_size;
+        let key_pos = util::get_data_size(&self.key_file)?;
+        util::ensure_size(
+            &self.key_file,
+            SUPERBLOCK_SIZE + key_pos + section_size as u64,
+        )?;
 
-        let mmap_key = get_rw_mmap_fd(&self.key_file, KEY_FILE_SIZE, key_pos);
-        self.km.keys = RwLock::new(mmap_key);
-        Ok((key_pos, buffer_pos, value_pos))
+        let mmap_key = get_rw_mmap_fd(&self.key_file, section_size, SUPERBLOCK_SIZE + key_pos);
+        *self.km.keys.write().unwrap() = mmap_key;
+        self.km.pos.store(0, Ordering::Relaxed);
+        Ok(key_pos)
     }
 
     fn init<P: AsRef<Path>>(
         key_file: P,
         value_file: P,
         buffer_file: P,
-        value_pos: u64,
+        options: StoreOptions,
     ) -> Result<Self, error::Error> {
+        let (section_size, buffer_size) = (options.section_size, options.buffer_size as u64);
         // Init buffer(mmap)
-        let mmap_buffer = get_rw_mmap_fd(&buffer_file, BUFFER_SIZE, 0);
-        let buf_pos = util::get_buffer_pos(&mmap_buffer)?;
+        let mmap_buffer = get_rw_mmap_fd(&buffer_file, options.buffer_size, SUPERBLOCK_SIZE);
 
         // Get values(dio) handle
-        let direct_file = DirectFile::open(&value_file, Mode::Open, FileAccess::ReadWrite, 4096)?;
+        let direct_file = DirectFile::open(
+            &value_file,
+            Mode::Open,
+            FileAccess::ReadWrite,
+            options.alignment,
+        )?;
+        // The buffer is always flushed as a whole, so the values file
+        // ends at the start of the buffer. Positions are counted after the superblock.
+        let value_pos = util::get_data_size(&value_file)? / buffer_size * buffer_size;
+
+        // Build index, from the checkpoint if any
+        let key_file_end = util::get_data_size(&key_file)?;
+        let (keys, replayed, checkpointed, replay_from) =
+            match util::load_index(util::with_suffix(&key_file, util::INDEX_SUFFIX))? {
+                Some(checkpoint) if checkpoint.key_end <= key_file_end => {
+                    let replayed =
+                        replay_keys(&key_file, checkpoint.key_end, key_file_end, section_size)?;
+                    (
+                        checkpoint.keys,
+                        replayed,
+                        checkpoint.ventry,
+                        checkpoint.key_end,
+                    )
+                }
+                _ => (
+                    build_index(&key_file, 0, key_file_end, section_size)?,
+                    Vec::new(),
+                    0,
+                    0,
+                ),
+            };
 
-        // Build index
-        let key_file_end = util::get_file_size(&key_file)?;
-        let index = build_index(&key_file, 0, key_file_end)?;
-        let ventry = index.len();
+        // Values written after the last key are not indexed, overwrite them
+        let last = keys.iter().chain(replayed.iter()).max_by_key(|k| k.ventry);
+        let value_end = last.map_or(0, Key::vend);
+        let buf_pos = value_end.saturating_sub(value_pos);
+        let ventry = last.map_or(0, |k| k.ventry + 1).max(checkpointed);
+
+        let mut index = (options.index)(keys);
+        for key in replayed {
+            index.insert(key);
+        }
 
         // Init keys(mmap)
-        let mmap_key = get_rw_mmap_fd(
-            &key_file,
-            KEY_FILE_SIZE,
-            key_file_end - KEY_FILE_SIZE as u64,
-        );
+        let section_start = key_file_end - section_size as u64;
+        let mmap_key = get_rw_mmap_fd(&key_file, section_size, SUPERBLOCK_SIZE + section_start);
+        // Records before the checkpoint are not parsed again
+        let scan_from = replay_from.saturating_sub(section_start) as usize;
+        let key_pos = scan_from as u64
+            + util::get_key_pos(
+                &key_file,
+                SUPERBLOCK_SIZE + section_start + scan_from as u64,
+                &mmap_key[scan_from..],
+            )?;
 
-        let km = KeyManager::new(mmap_key, index, ventry);
+        let km = KeyManager::new(mmap_key, index, ventry, key_pos as usize);
 
-        let vm = ValueManager::new(mmap_buffer, buf_pos, direct_file, value_pos);
+        let vm = ValueManager::new(mmap_buffer, buf_pos, direct_file, value_pos)
+            .with_files(&value_file, &buffer_file)
+            .with_cache(BlockCache::new(options.cache_size))
+            .with_sync(options.sync);
 
         Ok(Store {
             km,
@@ -119,224 +327,1037 @@ impl Store {
             key_file: key_file.as_ref().to_path_buf(),
             buffer_file: buffer_file.as_ref().to_path_buf(),
             value_file: value_file.as_ref().to_path_buf(),
+            options,
+            checkpointed: AtomicU64::new(checkpointed),
+            writer: Mutex::new(()),
+            saving: Mutex::new(()),
+            snapshots: Mutex::new(BTreeMap::new()),
+            lock: None,
         })
     }
 
-    pub fn get(&mut self, key: InnerKey) -> Result<Option<InnerValue>, error::Error> {
-        let key = self.km.find(&key);
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, error::Error> {
+        self.get_before(key, u64::MAX)
+    }
+
+    /// Get the values of several keys at once, in the order of `keys`
+    /// Values in the values file are read sorted by offset, records in
+    /// neighbouring blocks being merged into one read.
+    pub fn multi_get<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Vec<u8>>>, error::Error> {
+        let now = util::now_millis();
+        let found: Vec<Option<Key>> = keys
+            .iter()
+            .map(|key| {
+                self.km
+                    .find(&InnerKey::from(key.as_ref()))
+                    .filter(|k| !k.is_expired(now))
+            })
+            .collect();
+        let mut values = self
+            .vm
+            .read_many(&found.iter().flatten().cloned().collect::<Vec<_>>())?
+            .into_iter();
+        Ok(found
+            .iter()
+            .map(|key| match key.as_ref().and_then(|_| values.next()) {
+                Some(Value::Valid(val)) => Some(val),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Hits and misses of the value block cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.vm.cache.stats()
+    }
+
+    /// Take a snapshot of current store
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // Registered for index checkpoints to keep the versions it sees
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let ventry = self.km.committed();
+        *snapshots.entry(ventry).or_insert(0) += 1;
+        Snapshot {
+            store: self,
+            ventry,
+        }
+    }
+
+    /// Get the latest version written before `ventry`
+    fn get_before(&self, key: &[u8], ventry: u64) -> Result<Option<Vec<u8>>, error::Error> {
+        let key = self.km.find_before(&InnerKey::from(key), ventry);
         match key {
             None => Ok(None),
-            Some(k) => match self.vm.read(k.ventry)? {
+            Some(ref k) if k.is_expired(util::now_millis()) => Ok(None),
+            Some(k) => match self.vm.read(&k)? {
                 Value::Invalid => Ok(None),
-                Value::Valid(val) => Ok(Some(*val.clone())),
+                Value::Valid(val) => Ok(Some(val)),
             },
         }
     }
 
-    pub fn put(&mut self, key: InnerKey, value: Value) -> Result<(), error::Error> {
-        // Write to buffer
-        let should_flush = self.vm.write(value_to_bytes(&value))?;
-
-        // Update keys and index
-        self.km.put(&key);
-
-        // Check should flush to disk or not
-        if should_flush {
-            // Flush to disk
-            let file_pos = self.vm.flush();
-            // Check if need more space
-            if file_pos % VALUE_FILE_SIZE as u64 == 0 {
-                self.ensure_size()?;
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), error::Error> {
+        self.write_one(key, Value::Valid(value.to_vec()), 0)
+    }
+
+    /// Put kv pair which expires after `ttl`
+    /// Once expired, the key is absent for reads, and dropped by compaction
+    pub fn put_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), error::Error> {
+        // A ttl too long to represent never expires in practice
+        let ttl = ttl
+            .as_secs()
+            .saturating_mul(1000)
+            .saturating_add(u64::from(ttl.subsec_millis()));
+        // `0` means never expires
+        let expires = util::now_millis().saturating_add(ttl).max(1);
+        self.write_one(key, Value::Valid(value.to_vec()), expires)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), error::Error> {
+        self.write_one(key, Value::Invalid, 0)
+    }
+
+    /// Replace the value of `key` with `new` only if it currently is `expected`
+    /// `None` stands for an absent key, as `expected` or as `new`(delete)
+    /// Returns whether the swap applied
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, error::Error> {
+        {
+            // No other write between the check and the swap
+            let _writer = self.writer.lock().unwrap();
+            let current = self.get(key)?;
+            if current.as_deref() != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => self.write_locked(key, Value::Valid(value.to_vec()), 0)?,
+                // Already absent
+                None if current.is_none() => {}
+                None => self.write_locked(key, Value::Invalid, 0)?,
             }
         }
+        self.maybe_save_index()?;
+        Ok(true)
+    }
 
-        Ok(())
+    /// Put the kv pair only if the key is absent
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, error::Error> {
+        self.compare_and_swap(key, None, Some(value))
     }
 
-    pub fn delete(&mut self, key: InnerKey) -> Result<(), error::Error> {
-        self.put(key, Value::Invalid)
+    /// Delete the key only if its value is `expected`
+    pub fn delete_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool, error::Error> {
+        self.compare_and_swap(key, Some(expected), None)
     }
 
-    pub fn scan(&mut self) -> StoreIter {
+    pub fn scan(&self) -> StoreIter<'_> {
         StoreIter::new(self)
     }
+
+    /// Scan keys within `range`, ie `db.range(&b"k1"[..]..&b"k5"[..])`
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> StoreIter<'_> {
+        let start = to_inner_bound(range.start_bound());
+        let end = to_inner_bound(range.end_bound());
+        StoreIter::with_range(self, start.as_ref(), end.as_ref())
+    }
+
+    /// Scan keys from the last one
+    pub fn scan_rev(&self) -> Rev<StoreIter<'_>> {
+        self.scan().rev()
+    }
+
+    /// Scan keys within `range` from the last one
+    pub fn range_rev<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Rev<StoreIter<'_>> {
+        self.range(range).rev()
+    }
+
+    /// Scan keys from `start`(inclusive) to the end
+    pub fn range_from(&self, start: &[u8]) -> StoreIter<'_> {
+        self.range(start..)
+    }
+
+    /// Scan keys starting with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> StoreIter<'_> {
+        let start = InnerKey::from(prefix);
+        let end = util::prefix_end(prefix);
+        StoreIter::with_range(self, Bound::Included(&start), end.as_ref())
+    }
+
+    /// Garbage collection
+    /// Rewrite the latest valid(neither deleted nor expired) value of each key to a new values file,
+    /// and keys to a new keys file pointing to them, then swap the files.
+    /// See `util::recover_compaction` for crash safety.
+    pub fn compact(&mut self) -> Result<(), error::Error> {
+        let key_tmp = util::with_suffix(&self.key_file, util::COMPACT_SUFFIX);
+        let value_tmp = util::with_suffix(&self.value_file, util::COMPACT_SUFFIX);
+        // The values file is replaced below
+        self.vm.wait()?;
+        {
+            let mut keys = BufWriter::new(File::create(&key_tmp)?);
+            let mut values = BufWriter::new(File::create(&value_tmp)?);
+            superblock::write(&mut keys, FileKind::Keys, &self.options)?;
+            superblock::write(&mut values, FileKind::Values, &self.options)?;
+            let (section_size, buffer_size) = (self.options.section_size, self.options.buffer_size);
+            let mut key_pos = 0;
+            let mut value_pos = 0;
+            let mut ventry = 0;
+            let now = util::now_millis();
+
+            let rindex = self.km.index.read().unwrap();
+            for versions in rindex.range(Bound::Unbounded, Bound::Unbounded) {
+                // The latest version only
+                let key = &versions[versions.len() - 1];
+                if key.is_expired(now) {
+                    continue;
+                }
+                let value = self.vm.read(key)?;
+                if let Value::Invalid = value {
+                    continue;
+                }
+
+                // Records never span two buffers
+                let vbytes = value_to_bytes(&value);
+                let rest = buffer_size - value_pos % buffer_size;
+                if vbytes.len() > rest {
+                    util::pad(&mut values, rest as u64)?;
+                    value_pos += rest;
+                }
+                let new_key = Key {
+                    inner: key.inner.clone(),
+                    ventry,
+                    vptr: value_pos as u64,
+                    vlen: key.vlen,
+                    flags: 0,
+                    expires: key.expires,
+                };
+                values.write_all(&vbytes)?;
+                value_pos += vbytes.len();
+
+                // Nor two sections of keys
+                let kbytes = key_to_bytes(&new_key);
+                let rest = section_size - key_pos % section_size;
+                if kbytes.len() > rest {
+                    util::pad(&mut keys, rest as u64)?;
+                    key_pos += rest;
+                }
+                keys.write_all(&kbytes)?;
+                key_pos += kbytes.len();
+                ventry += 1;
+            }
+
+            // Values file holds whole buffers only, so the buffer starts empty
+            let rest = (buffer_size - value_pos % buffer_size) % buffer_size;
+            util::pad(&mut values, rest as u64)?;
+            let rest = section_size - key_pos % section_size;
+            if key_pos == 0 || rest < section_size {
+                util::pad(&mut keys, rest as u64)?;
+            }
+
+            values
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            keys.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+
+        // The checkpoint indexes the old keys file
+        let index_file = util::with_suffix(&self.key_file, util::INDEX_SUFFIX);
+        if index_file.exists() {
+            fs::remove_file(index_file)?;
+        }
+
+        // Commit
+        fs::rename(
+            &key_tmp,
+            util::with_suffix(&self.key_file, util::COMPACTED_SUFFIX),
+        )?;
+        util::sync_dir(&self.key_file)?;
+        util::recover_compaction(&self.key_file, &self.value_file)?;
+
+        let mut store = Store::init(
+            &self.key_file,
+            &self.value_file,
+            &self.buffer_file,
+            self.options,
+        )?;
+        store.lock = self.lock.take();
+        *self = store;
+        Ok(())
+    }
+
+    /// Apply all operations of `batch` atomically
+    /// After a restart either all or none of them are visible
+    pub fn write(&self, batch: WriteBatch) -> Result<(), error::Error> {
+        for (key, value) in batch.iter() {
+            check(key, value, &self.options)?;
+        }
+        {
+            let _writer = self.writer.lock().unwrap();
+            self.reserve(batch.len() as u64)?;
+            let last = batch.len().saturating_sub(1);
+            let mut keys = Vec::with_capacity(batch.len());
+            for (i, (key, value)) in batch.into_iter().enumerate() {
+                let flags = if i == last {
+                    FLAG_BATCH | FLAG_BATCH_END
+                } else {
+                    FLAG_BATCH
+                };
+                keys.push(self.append(&key, value, flags, 0)?);
+            }
+            self.sync()?;
+            // Only visible once the whole batch is written
+            self.km.insert(keys);
+        }
+        self.maybe_save_index()
+    }
+
+    fn write_one(&self, key: &[u8], value: Value, expires: u64) -> Result<(), error::Error> {
+        {
+            let _writer = self.writer.lock().unwrap();
+            self.write_locked(key, value, expires)?;
+        }
+        self.maybe_save_index()
+    }
+
+    /// `write_one` with the writer lock held
+    fn write_locked(&self, key: &[u8], value: Value, expires: u64) -> Result<(), error::Error> {
+        check(key, &value, &self.options)?;
+        self.reserve(1)?;
+        let key = self.append(key, value, 0, expires)?;
+        self.sync()?;
+        self.km.insert(vec![key]);
+        Ok(())
+    }
+
+    /// Checkpoint the index, so that opening the store only replays
+    /// the keys written after it
+    pub fn save_index(&self) -> Result<(), error::Error> {
+        let _saving = self.saving.lock().unwrap();
+        self.checkpoint_index()
+    }
+
+    /// `save_index` with `saving` held
+    /// Only capturing where the keys end takes the writer lock.
+    fn checkpoint_index(&self) -> Result<(), error::Error> {
+        let (key_end, ventry) = {
+            let _writer = self.writer.lock().unwrap();
+            // The values file must hold the flushed buffers before the checkpoint
+            self.vm.wait()?;
+            (self.key_end()?, self.km.next_ventry())
+        };
+        File::open(&self.key_file)?.sync_all()?;
+        self.write_index(&self.key_file, key_end, ventry)?;
+        self.checkpointed.store(ventry, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// End of the key records, the writer lock held
+    fn key_end(&self) -> Result<u64, error::Error> {
+        Ok(
+            util::get_data_size(&self.key_file)? - self.options.section_size as u64
+                + self.km.pos() as u64,
+        )
+    }
+
+    /// Write the index checkpoint of the records before `key_end`, whose ventries are below `ventry`,
+    /// for `key_file`. It keeps the latest of those versions of each key, and the ones live snapshots see.
+    /// The index is read a chunk of keys at a time, writes go on meanwhile.
+    fn write_index(&self, key_file: &Path, key_end: u64, ventry: u64) -> Result<(), error::Error> {
+        let snapshots = self.snapshots.lock().unwrap().clone();
+        let mut writer = IndexWriter::create(
+            util::with_suffix(key_file, util::INDEX_SUFFIX),
+            key_end,
+            ventry,
+        )?;
+        let mut start = Bound::Unbounded;
+        loop {
+            let mut chunk = Vec::new();
+            {
+                let rindex = self.km.index.read().unwrap();
+                let mut visited = 0;
+                for versions in rindex
+                    .range(start.as_ref(), Bound::Unbounded)
+                    .take(INDEX_CHECKPOINT_CHUNK)
+                {
+                    chunk.extend(checkpointed_versions(versions, ventry, &snapshots).cloned());
+                    start = Bound::Excluded(versions[0].inner.clone());
+                    visited += 1;
+                }
+                if visited == 0 {
+                    break;
+                }
+            }
+            for key in chunk.iter() {
+                writer.append(key)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Online backup: copy the store into `dir`, as files `Store::new` can open
+    /// The copy holds the writes before the returned ventry, with an index checkpoint.
+    /// Writes go on meanwhile, the writer lock is only held to capture where the files end
+    /// and the records of the buffer. Files are copied by `fs::copy`, which reflinks them
+    /// where the filesystem can, then cut at those ends.
+    /// The copy is written to a tmp directory renamed to `dir`, which must not exist or be empty.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<u64, error::Error> {
+        let dir = dir.as_ref();
+        if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", dir),
+            )
+            .into());
+        }
+        let (key_end, ventry, file_pos, buffered) = {
+            let _writer = self.writer.lock().unwrap();
+            // The values file must hold the flushed buffers
+            self.vm.wait()?;
+            let (file_pos, buffered) = self.vm.buffered();
+            (self.key_end()?, self.km.next_ventry(), file_pos, buffered)
+        };
+
+        // Left by a checkpoint which failed or crashed
+        let tmp = util::with_suffix(dir, util::COMPACT_SUFFIX);
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        let copied = self.copy_to(&tmp, key_end, ventry, file_pos, &buffered);
+        if let Err(e) = copied {
+            // Best effort, a leftover is removed by the next checkpoint
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(e);
+        }
+        fs::rename(&tmp, dir)?;
+        util::sync_dir(dir)?;
+        Ok(ventry)
+    }
+
+    /// Copy the files of the store into `dir`, up to the ends captured by `checkpoint`
+    fn copy_to(
+        &self,
+        dir: &Path,
+        key_end: u64,
+        ventry: u64,
+        file_pos: u64,
+        buffered: &[u8],
+    ) -> Result<(), error::Error> {
+        let dest = |path: &Path| dir.join(path.file_name().expect("db files are named"));
+        let section_size = self.options.section_size as u64;
+
+        // The values file ends with the flushed buffers
+        let value_dest = dest(&self.value_file);
+        fs::copy(&self.value_file, &value_dest)?;
+        let values = OpenOptions::new().write(true).open(&value_dest)?;
+        values.set_len(SUPERBLOCK_SIZE + file_pos)?;
+        values.sync_all()?;
+
+        // The buffer holds the records captured, the rest of it zeroed
+        let buffer_dest = dest(&self.buffer_file);
+        {
+            let mut buffer = BufWriter::new(File::create(&buffer_dest)?);
+            superblock::write(&mut buffer, FileKind::Buffer, &self.options)?;
+            buffer.write_all(buffered)?;
+            util::pad(
+                &mut buffer,
+                (self.options.buffer_size - buffered.len()) as u64,
+            )?;
+            buffer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+        }
+
+        // The keys file ends with the section holding `key_end`, zeroed after it
+        let key_dest = dest(&self.key_file);
+        fs::copy(&self.key_file, &key_dest)?;
+        let keys = OpenOptions::new().write(true).open(&key_dest)?;
+        let section_end = (key_end / section_size + 1) * section_size;
+        keys.set_len(SUPERBLOCK_SIZE + key_end)?;
+        keys.set_len(SUPERBLOCK_SIZE + section_end)?;
+        keys.sync_all()?;
+
+        self.write_index(&key_dest, key_end, ventry)?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Incremental backup: write the records of the writes since ventry `since`
+    /// (the watermark of the previous backup) to the delta file `path`.
+    /// Returns the ventry it ends at, the watermark of the next delta.
+    /// Compaction renumbers ventries, a chain of deltas starts over from a checkpoint after it.
+    pub fn export_delta<P: AsRef<Path>>(&self, since: u64, path: P) -> Result<u64, error::Error> {
+        // Where the committed records end, writes appending meanwhile are left to the next delta
+        let (key_end, to) = {
+            let _writer = self.writer.lock().unwrap();
+            (self.key_end()?, self.km.committed())
+        };
+        if since > to {
+            return Err(error::Error::InvalidDelta {
+                file: path.as_ref().to_path_buf(),
+                reason: format!("watermark {} is ahead of the store at {}", since, to),
+            });
+        }
+        // Records of the keys file in the order they were written, committed batches only.
+        // Those before the index checkpoint are older than `since` if it is.
+        let from =
+            match util::load_index_header(util::with_suffix(&self.key_file, util::INDEX_SUFFIX))? {
+                Some((checkpoint_end, ventry)) if ventry <= since && checkpoint_end <= key_end => {
+                    checkpoint_end
+                }
+                _ => 0,
+            };
+        let keys = replay_keys(&self.key_file, from, key_end, self.options.section_size)?;
+        let mut writer = DeltaWriter::create(&path, since)?;
+        for key in keys
+            .iter()
+            .filter(|key| key.ventry >= since && key.ventry < to)
+        {
+            writer.append(key, &self.vm.read(key)?)?;
+        }
+        writer.finish(to)?;
+        Ok(to)
+    }
+
+    /// Restore: apply a chain of deltas, exported by `export_delta`, on top of the store,
+    /// ie a copy made by `checkpoint`. The chain is checked before anything is written:
+    /// the first delta starts at the ventry of the store, each next one where the previous ends.
+    /// Records keep their ventries, flags and expiry, a batch is applied as a whole.
+    /// Returns the ventry the store is at.
+    pub fn restore<P: AsRef<Path>>(&mut self, deltas: &[P]) -> Result<u64, error::Error> {
+        let mut ventry = self.km.next_ventry();
+        for path in deltas {
+            let header = DeltaReader::open(path)?.header().clone();
+            if header.from != ventry {
+                return Err(error::Error::InvalidDelta {
+                    file: path.as_ref().to_path_buf(),
+                    reason: format!("starts at ventry {}, expected {}", header.from, ventry),
+                });
+            }
+            ventry = header.to;
+        }
+        for path in deltas {
+            self.apply_delta(path.as_ref())?;
+        }
+        Ok(self.km.next_ventry())
+    }
+
+    fn apply_delta(&mut self, path: &Path) -> Result<(), error::Error> {
+        let mut reader = DeltaReader::open(path)?;
+        let to = reader.header().to;
+        // Records of a batch not committed yet
+        let mut batch = Vec::new();
+        for record in &mut reader {
+            let (key, value) = record?;
+            let next = self.km.next_ventry();
+            if key.ventry < next || key.ventry >= to {
+                return Err(error::Error::InvalidDelta {
+                    file: path.to_path_buf(),
+                    reason: format!(
+                        "record of ventry {}, expected {} to {}",
+                        key.ventry, next, to
+                    ),
+                });
+            }
+            check(&key.inner.raw, &value, &self.options)?;
+            // Writes missing from the source, ie a discarded batch, keep their ventries unused
+            self.km.skip_to(key.ventry);
+            batch.push(self.append(&key.inner.raw, value, key.flags, key.expires)?);
+            if key.flags & FLAG_BATCH == 0 || key.flags & FLAG_BATCH_END != 0 {
+                self.sync()?;
+                self.km.insert(std::mem::take(&mut batch));
+            }
+        }
+        // The index checkpoint keeps the ventry the delta ends at across reopening
+        self.km.skip_to(to);
+        self.save_index()
+    }
+
+    /// Checkpoint the index every `INDEX_CHECKPOINT_INTERVAL` writes, after the write
+    /// released the writer lock. Skipped while another checkpoint is being written.
+    fn maybe_save_index(&self) -> Result<(), error::Error> {
+        let due = || {
+            let checkpointed = self.checkpointed.load(Ordering::Relaxed);
+            self.km.next_ventry() - checkpointed >= INDEX_CHECKPOINT_INTERVAL
+        };
+        if due() {
+            if let Ok(_saving) = self.saving.try_lock() {
+                if due() {
+                    self.checkpoint_index()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync the mapped keys and buffer with `SyncPolicy::Always`
+    fn sync(&self) -> Result<(), error::Error> {
+        if self.options.sync == SyncPolicy::Always {
+            self.km.keys.read().unwrap().flush()?;
+            self.vm.buf.read().unwrap().mmap.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Check that `n` more records can get a ventry
+    fn reserve(&self, n: u64) -> Result<(), error::Error> {
+        if n > u64::MAX - self.km.next_ventry() {
+            return Err(error::Error::LimitReached);
+        }
+        Ok(())
+    }
+
+    /// Write the value and the key record, without updating the index
+    fn append(
+        &self,
+        key: &[u8],
+        value: Value,
+        flags: u8,
+        expires: u64,
+    ) -> Result<Key, error::Error> {
+        // Flush to disk when the buffer can not hold the record
+        let record = value_to_bytes(&value);
+        if !self.vm.fits(record.len()) {
+            self.vm.flush()?;
+        }
+
+        // Write to buffer
+        let vptr = self.vm.write(&record)?;
+
+        // Check if need more space for keys
+        if !self.km.fits(key.len()) {
+            self.ensure_size()?;
+        }
+
+        // Update keys
+        Ok(self
+            .km
+            .append(&InnerKey::from(key), vptr, value.vlen(), flags, expires))
+    }
 }
 
+fn check(key: &[u8], value: &Value, options: &StoreOptions) -> Result<(), error::Error> {
+    if key.is_empty() {
+        return Err(error::Error::InvalidKeySize);
+    }
+    if key.len() > MAX_KEY_SIZE {
+        return Err(error::Error::ContentExceed);
+    }
+    if let Value::Valid(v) = value {
+        if v.len() > options.max_value_size() {
+            return Err(error::Error::ContentExceed);
+        }
+    }
+    Ok(())
+}
+
+fn to_inner_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<InnerKey> {
+    match bound {
+        Bound::Included(key) => Bound::Included(InnerKey::from(key.as_ref())),
+        Bound::Excluded(key) => Bound::Excluded(InnerKey::from(key.as_ref())),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Versions an index checkpoint taken at `watermark` keeps among `versions` of a key:
+/// the latest one before it, and the latest one before each live snapshot
+fn checkpointed_versions<'a>(
+    versions: &'a [Key],
+    watermark: u64,
+    snapshots: &'a BTreeMap<u64, usize>,
+) -> impl Iterator<Item = &'a Key> {
+    versions
+        .iter()
+        .enumerate()
+        .filter(move |(i, key)| {
+            if key.ventry >= watermark {
+                return false;
+            }
+            // Seen by the snapshots between this version and the next one
+            let next = versions
+                .get(i + 1)
+                .map_or(watermark, |next| next.ventry.min(watermark));
+            next == watermark || snapshots.range(key.ventry + 1..=next).next().is_some()
+        })
+        .map(|(_, key)| key)
+}
+
+/// Largest read `ValueManager::read_many` merges records into
+const MAX_MERGED_READ: u64 = 256 * 1024;
+
 pub struct ValueManager {
-    buf: RwLock<MmapMut>,
-    buf_pos: u64,
+    buf: RwLock<Buffer>,
+    buffer_size: usize,
     file: RwLock<DirectFile>,
+    // Shared by readers
+    cache: BlockCache,
+    sync: SyncPolicy,
+    // For reporting corrupted records
+    value_file: PathBuf,
+    buffer_file: PathBuf,
+}
+
+/// The mapped buffer and where it goes in the values file,
+/// updated together so that readers find a record where it is.
+struct Buffer {
+    mmap: MappedRegion,
+    pos: u64,
     file_pos: u64,
-    cache: PageCache,
+    // The last flushed buffer, until its write completes
+    pending: Option<PendingWrite>,
+}
+
+impl Buffer {
+    /// Position of the buffer being written, after the superblock
+    fn pending_pos(&self) -> Option<u64> {
+        self.pending
+            .as_ref()
+            .map(|pending| pending.offset() - SUPERBLOCK_SIZE)
+    }
 }
 
 impl ValueManager {
-    pub fn new(mmap_buffer: MmapMut, buf_pos: u64, direct_file: DirectFile, file_pos: u64) -> Self {
+    pub fn new(
+        mmap_buffer: MappedRegion,
+        buf_pos: u64,
+        direct_file: DirectFile,
+        file_pos: u64,
+    ) -> Self {
         ValueManager {
-            buf: RwLock::new(mmap_buffer),
-            buf_pos,
+            buffer_size: mmap_buffer.len(),
+            buf: RwLock::new(Buffer {
+                mmap: mmap_buffer,
+                pos: buf_pos,
+                file_pos,
+                pending: None,
+            }),
             file: RwLock::new(direct_file),
-            file_pos,
-            cache: PageCache::new(),
+            cache: BlockCache::new(DEFAULT_CACHE_SIZE),
+            sync: SyncPolicy::Never,
+            value_file: PathBuf::new(),
+            buffer_file: PathBuf::new(),
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<bool, error::Error> {
-        if buf.len() != VALUE_SIZE {
+    pub fn with_cache(mut self, cache: BlockCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Sync the values file after each flush unless `SyncPolicy::Never`
+    pub fn with_sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Name the files holding the values in errors
+    pub fn with_files<P: AsRef<Path>>(mut self, value_file: P, buffer_file: P) -> Self {
+        self.value_file = value_file.as_ref().to_path_buf();
+        self.buffer_file = buffer_file.as_ref().to_path_buf();
+        self
+    }
+
+    /// Where the buffer goes in the values file, and a copy of its records
+    pub fn buffered(&self) -> (u64, Vec<u8>) {
+        let rbuf = self.buf.read().unwrap();
+        (rbuf.file_pos, rbuf.mmap[..rbuf.pos as usize].to_vec())
+    }
+
+    /// Check whether the buffer has room for `len` bytes
+    pub fn fits(&self, len: usize) -> bool {
+        self.buf.read().unwrap().pos as usize + len <= self.buffer_size
+    }
+
+    /// Append a record to the buffer
+    /// Returns the position of the record
+    pub fn write(&self, buf: &[u8]) -> Result<u64, error::Error> {
+        let mut wbuf = self.buf.write().unwrap();
+        if wbuf.pos as usize + buf.len() > self.buffer_size {
             return Err(error::Error::InvalidValueSize);
         }
-        let mut wbuf = self.buf.write().unwrap();
 
-        let mut index = 0;
+        let pos = wbuf.pos as usize;
+        wbuf.mmap[pos..pos + buf.len()].copy_from_slice(buf);
 
-        while index < VALUE_SIZE {
-            wbuf[self.buf_pos as usize + index] = buf[index];
-            index += 1;
-        }
+        let vptr = wbuf.file_pos + wbuf.pos;
+        wbuf.pos += buf.len() as u64;
 
-        self.buf_pos += VALUE_SIZE as u64;
+        Ok(vptr)
+    }
 
-        Ok(self.buf_pos >= BUFFER_SIZE as u64)
+    /// Write the buffer to the values file, without waiting for it
+    /// Only one write is in flight, the previous one is waited first.
+    /// Unless `SyncPolicy::Never`, the write is waited and synced right away.
+    /// The buffer is not locked while written, writes are serialized by the store.
+    /// Returns the end of the values file once written
+    pub fn flush(&self) -> Result<u64, error::Error> {
+        self.wait()?;
+        // The buffer is reused right away, so the write gets a copy.
+        // The rest of it is zeroed, records never span two buffers.
+        let (aligned, file_pos) = {
+            let rbuf = self.buf.read().unwrap();
+            let mut aligned = AlignedBuf::new(self.buffer_size);
+            let len = rbuf.pos as usize;
+            aligned.as_mut_slice()[..len].copy_from_slice(&rbuf.mmap[..len]);
+            (aligned, rbuf.file_pos)
+        };
+        // Readers find the records in the buffer until the write is pending
+        let pending = self
+            .file
+            .read()
+            .unwrap()
+            .write_async(aligned, SUPERBLOCK_SIZE + file_pos)?;
+        let file_pos = {
+            let mut wbuf = self.buf.write().unwrap();
+            wbuf.pending = Some(pending);
+            wbuf.file_pos += self.buffer_size as u64;
+            wbuf.pos = 0;
+            wbuf.file_pos
+        };
+        if self.sync != SyncPolicy::Never {
+            self.wait()?;
+            self.file.read().unwrap().sync_data()?;
+        }
+        Ok(file_pos)
     }
 
-    pub fn flush(&mut self) -> u64 {
-        // Do flush
-        let mut wbuf = self.buf.write().unwrap();
-        let wfile = self.file.write().unwrap();
-        // wbuf must be a multiple of the page size(512 kb)
-        let bytes = wfile
-            .pwrite(&wbuf, self.file_pos as u64)
-            .expect("Failed to append to db file");
-        self.file_pos += bytes as u64;
-
-        // Clear buffer
-        wbuf.copy_from_slice(&[0u8; BUFFER_SIZE]);
-        self.buf_pos = 0;
-        self.file_pos
-    }
-
-    pub fn read(&mut self, ventry: usize) -> Result<Value, error::Error> {
-        let mut offset = ventry * VALUE_SIZE;
-        if offset as u64 > self.file_pos + BUFFER_SIZE as u64 {
-            Err(error::Error::OutOfIndex)
-        } else if offset as u64 >= self.file_pos {
-            // Value is in buffer
+    /// Wait for the write in flight, if any
+    /// Readers find the records in the pending write until it completes.
+    /// A write which failed, or may still be in flight, is kept: its records are
+    /// read from it and the next flush fails waiting for it again.
+    pub fn wait(&self) -> Result<(), error::Error> {
+        let written = {
+            let rbuf = self.buf.read().unwrap();
+            match rbuf.pending.as_ref() {
+                Some(pending) => self.file.read().unwrap().wait(pending)?,
+                None => return Ok(()),
+            }
+        };
+        if written < self.buffer_size {
+            return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+        }
+        self.buf.write().unwrap().pending = None;
+        Ok(())
+    }
+
+    /// Read the values of `keys`, in order
+    /// Records in the values file are merged into aligned reads of adjacent
+    /// blocks, up to `MAX_MERGED_READ` bytes, submitted together.
+    /// Those reads bypass the block cache.
+    pub fn read_many(&self, keys: &[Key]) -> Result<Vec<Value>, error::Error> {
+        let mut values: Vec<Option<Value>> = keys.iter().map(|_| None).collect();
+        // Records being written or in the buffer are read as usual
+        let file_end = {
             let rbuf = self.buf.read().unwrap();
-            offset -= self.file_pos as usize;
-            let data = &rbuf[offset..offset + VALUE_SIZE];
-            Ok(value_from_bytes(data).unwrap())
-        } else {
-            // Value is in file
-            // try get value from page cache here
-            let v = self.cache.try_get(offset as u64, VALUE_SIZE as u64);
-            match v {
-                None => {
-                    // Read from dio
-                    let rfile = self.file.read().unwrap();
-                    let bytes = self
-                        .cache
-                        .try_load(&rfile, VALUE_SIZE as u64, offset as u64)?;
-                    Ok(value_from_bytes(&bytes).unwrap())
+            rbuf.pending_pos().unwrap_or(rbuf.file_pos)
+        };
+        let mut in_file = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if key.vptr < file_end {
+                in_file.push(i);
+            } else {
+                values[i] = Some(self.read(key)?);
+            }
+        }
+
+        // (start, end, records) of each read
+        in_file.sort_by_key(|&i| keys[i].vptr);
+        let mut reads: Vec<(u64, u64, Vec<usize>)> = Vec::new();
+        for i in in_file {
+            let start = keys[i].vptr;
+            let end = start + value_record_size(keys[i].vlen) as u64;
+            match reads.last_mut() {
+                Some((first, last, records))
+                    if start / BLOCK_SIZE <= (*last - 1) / BLOCK_SIZE + 1
+                        && end - *first <= MAX_MERGED_READ =>
+                {
+                    *last = end.max(*last);
+                    records.push(i);
                 }
-                Some(bytes) => Ok(value_from_bytes(&bytes).unwrap()),
+                _ => reads.push((start, end, vec![i])),
+            }
+        }
+
+        let reqs: Vec<_> = reads
+            .iter()
+            .map(|(start, end, _)| (SUPERBLOCK_SIZE + *start, (end - start) as usize))
+            .collect();
+        let bytes = self.file.read().unwrap().read_many(&reqs)?;
+        for ((start, _, records), bytes) in reads.iter().zip(bytes) {
+            for &i in records {
+                let key = &keys[i];
+                let pos = (key.vptr - start) as usize;
+                let data = &bytes[pos..pos + value_record_size(key.vlen)];
+                values[i] = Some(value_from_bytes(data).map_err(|_| error::Error::Corrupted {
+                    file: self.value_file.clone(),
+                    offset: SUPERBLOCK_SIZE + key.vptr,
+                })?);
             }
         }
+        Ok(values.into_iter().map(Option::unwrap).collect())
     }
-}
 
-/// Read 4k block values as cache
-struct PageCache {
-    cache: Block4k,
-    start: u64,
-    end: u64,
+    pub fn read(&self, key: &Key) -> Result<Value, error::Error> {
+        let offset = key.vptr;
+        let len = value_record_size(key.vlen) as u64;
+        {
+            let rbuf = self.buf.read().unwrap();
+            if offset + len > rbuf.file_pos + rbuf.pos {
+                return Err(error::Error::OutOfIndex);
+            } else if offset >= rbuf.file_pos {
+                // Value is in buffer
+                let pos = (offset - rbuf.file_pos) as usize;
+                let data = &rbuf.mmap[pos..pos + len as usize];
+                return value_from_bytes(data).map_err(|_| error::Error::Corrupted {
+                    file: self.buffer_file.clone(),
+                    offset: SUPERBLOCK_SIZE + pos as u64,
+                });
+            } else if let Some(pending_pos) = rbuf.pending_pos().filter(|&start| offset >= start) {
+                // Value is being written, the file may not hold it yet
+                let pos = (offset - pending_pos) as usize;
+                let data = &rbuf.pending.as_ref().unwrap().bytes()[pos..pos + len as usize];
+                return value_from_bytes(data).map_err(|_| error::Error::Corrupted {
+                    file: self.value_file.clone(),
+                    offset: SUPERBLOCK_SIZE + offset,
+                });
+            }
+        }
+        // Value is in file, read it through the block cache
+        // Written records stay there, the buffer is not locked meanwhile.
+        let rfile = self.file.read().unwrap();
+        let bytes = self
+            .cache
+            .read(&rfile, SUPERBLOCK_SIZE + offset, len as usize)?;
+        value_from_bytes(&bytes).map_err(|_| error::Error::Corrupted {
+            file: self.value_file.clone(),
+            offset: SUPERBLOCK_SIZE + offset,
+        })
+    }
 }
 
-impl PageCache {
-    pub fn new() -> Self {
-        PageCache {
-            cache: Block4k { bytes: [0; 4096] },
-            start: 0,
-            end: 0,
-        }
-    }
-    pub fn try_load(
-        &mut self,
-        dio_file: &DirectFile,
-        len: u64,
-        offset: u64,
-    ) -> Result<Vec<u8>, error::Error> {
-        const PAGE_SIZE: u64 = 512;
-        const CACHE_SIZE: u64 = 4096;
-        let md = offset % PAGE_SIZE;
-        if len - md > CACHE_SIZE {
-            return Err(error::Error::CacheTooSmall);
-        }
-        let read = dio_file.pread(&mut self.cache.bytes, offset - md)?;
-        self.start = offset - md;
-        self.end = self.start + read;
-        let v = self.try_get(offset, len).unwrap();
-        Ok(v)
-    }
-    pub fn try_get(&self, offset: u64, len: u64) -> Option<Vec<u8>> {
-        if offset >= self.start && offset + len <= self.end {
-            let pos = (offset - self.start) as usize;
-            return Some(Vec::from(&self.cache.bytes[pos..pos + len as usize]));
-        }
-        None
+impl Drop for ValueManager {
+    fn drop(&mut self) {
+        // The pending write holds the buffer the kernel writes from
+        if let Err(e) = self.wait() {
+            eprintln!("Failed to write values: {}", e);
+        }
     }
 }
 
+/// Keys are appended by one writer at a time, see `Store`
+/// Readers only go through the index.
 pub struct KeyManager {
-    keys: RwLock<MmapMut>,
-    index: RwLock<Vec<Key>>,
-    ventry: usize,
+    keys: RwLock<MappedRegion>,
+    section_size: usize,
+    index: RwLock<Box<dyn Index>>,
+    // Next ventry to write, and position in the mapped section,
+    // only changed by the writer
+    ventry: AtomicU64,
+    pos: AtomicUsize,
+    // Versions before it are indexed, the ventry of a snapshot taken now
+    committed: AtomicU64,
 }
 
 impl KeyManager {
-    pub fn new(mmap_key: MmapMut, index: Vec<Key>, ventry: usize) -> Self {
+    pub fn new(mmap_key: MappedRegion, index: Box<dyn Index>, ventry: u64, pos: usize) -> Self {
         KeyManager {
+            section_size: mmap_key.len(),
             keys: RwLock::new(mmap_key),
             index: RwLock::new(index),
-            ventry,
+            ventry: AtomicU64::new(ventry),
+            pos: AtomicUsize::new(pos),
+            committed: AtomicU64::new(ventry),
         }
     }
 
     pub fn find(&self, inner: &InnerKey) -> Option<Key> {
         let rindex = self.index.read().unwrap();
-        let kentry = bsearch(&*rindex, &inner);
-        match kentry {
-            None => None,
-            Some(entry) => Some(rindex[entry].clone()),
-        }
+        rindex
+            .versions(inner)
+            .and_then(|versions| versions.last().cloned())
     }
 
-    pub fn put(&mut self, key: &InnerKey) {
-        let mut windex = self.index.write().unwrap();
+    /// Find the latest version written before `ventry`
+    pub fn find_before(&self, inner: &InnerKey, ventry: u64) -> Option<Key> {
+        let rindex = self.index.read().unwrap();
+        // Versions of a key are ordered by ventry
+        rindex
+            .versions(inner)?
+            .iter()
+            .rev()
+            .find(|key| key.ventry < ventry)
+            .cloned()
+    }
+
+    /// ventry the next key is written with
+    pub fn next_ventry(&self) -> u64 {
+        self.ventry.load(Ordering::Relaxed)
+    }
+
+    /// ventry before which every written key is indexed
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::Acquire)
+    }
+
+    /// Position of the next key in the mapped section
+    pub fn pos(&self) -> usize {
+        self.pos.load(Ordering::Relaxed)
+    }
+
+    /// Continue writing at `ventry`, the ones skipped stay unused
+    pub fn skip_to(&self, ventry: u64) {
+        self.ventry.store(ventry, Ordering::Relaxed);
+        self.committed.store(ventry, Ordering::Release);
+    }
+
+    /// Check whether the mapped section has room for a key of `klen` bytes
+    pub fn fits(&self, klen: usize) -> bool {
+        self.pos() + key_record_size(klen) <= self.section_size
+    }
+
+    /// Append a key record to the mapped section
+    /// The returned key is not indexed until `insert`
+    pub fn append(&self, key: &InnerKey, vptr: u64, vlen: u32, flags: u8, expires: u64) -> Key {
         let mut wkeys = self.keys.write().unwrap();
 
-        let ventry = windex.len();
         let new_key = Key {
             inner: key.clone(),
-            ventry,
+            ventry: self.next_ventry(),
+            vptr,
+            vlen,
+            flags,
+            expires,
         };
         let kbytes = key_to_bytes(&new_key);
 
-        // Update index
-        let (_found, pos) = find_insert_point(&windex, key);
-
-        if pos == windex.len() {
-            windex.push(new_key);
-        } else {
-            windex.insert(pos, new_key);
-        }
-
         // Append to keys (mmap)
-        let offset = ventry % MAX_KV_PAIR * MKEY_SIZE;
+        let pos = self.pos();
+        wkeys[pos..pos + kbytes.len()].copy_from_slice(&kbytes);
+        self.pos.store(pos + kbytes.len(), Ordering::Relaxed);
 
-        for pos in offset..offset + MKEY_SIZE {
-            wkeys[pos] = kbytes[pos - offset];
-        }
+        self.ventry.store(new_key.ventry + 1, Ordering::Relaxed);
+        new_key
+    }
 
-        self.ventry = windex.len();
+    /// Index the keys appended so far, readers see all of them at once
+    pub fn insert(&self, keys: Vec<Key>) {
+        let mut windex = self.index.write().unwrap();
+        for key in keys {
+            windex.insert(key);
+        }
+        self.committed.store(self.next_ventry(), Ordering::Release);
     }
 }
@@ -0,0 +1,382 @@
+use super::error::Error;
+use super::kv::*;
+use super::options::StoreOptions;
+use super::util;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Size of the superblock at the head of each db file, data follows it
+/// A whole block, so that the data stays aligned for direct io.
+pub const SUPERBLOCK_SIZE: u64 = 4096;
+/// Leading bytes of every db file
+pub const MAGIC: &[u8; 8] = b"TOYKV\0\0\0";
+/// Version of the record formats, bumped on incompatible changes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Which db file a superblock belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    Keys,
+    Values,
+    Buffer,
+}
+
+impl FileKind {
+    fn tag(self) -> u8 {
+        match self {
+            FileKind::Keys => b'k',
+            FileKind::Values => b'v',
+            FileKind::Buffer => b'b',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            b'k' => Some(FileKind::Keys),
+            b'v' => Some(FileKind::Values),
+            b'b' => Some(FileKind::Buffer),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FileKind::Keys => "keys",
+            FileKind::Values => "values",
+            FileKind::Buffer => "buffer",
+        }
+    }
+}
+
+/// Header of a db file: format version and the geometry it was written with
+/// [magic(8 bytes)][crc(4 bytes)][version(4 bytes)][kind(1 byte)]
+/// [key header size(4 bytes)][value header size(4 bytes)][max key size(4 bytes)]
+/// [section size(8 bytes)][buffer size(8 bytes)], padded with zeros
+/// crc covers the rest of the superblock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Superblock {
+    pub kind: FileKind,
+    pub version: u32,
+    pub key_header_size: u32,
+    pub value_header_size: u32,
+    pub max_key_size: u32,
+    /// size of each keys section
+    pub section_size: u64,
+    /// size of the buffer, the values file grows by it
+    pub buffer_size: u64,
+}
+
+impl Superblock {
+    /// Superblock of a file written by this version with the geometry of `options`
+    pub fn new(kind: FileKind, options: &StoreOptions) -> Self {
+        Superblock {
+            kind,
+            version: FORMAT_VERSION,
+            key_header_size: KEY_HEADER_SIZE as u32,
+            value_header_size: VALUE_HEADER_SIZE as u32,
+            max_key_size: MAX_KEY_SIZE as u32,
+            section_size: options.section_size as u64,
+            buffer_size: options.buffer_size as u64,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; SUPERBLOCK_SIZE as usize];
+        bytes[0..8].copy_from_slice(MAGIC);
+        BigEndian::write_u32(&mut bytes[12..16], self.version);
+        bytes[16] = self.kind.tag();
+        BigEndian::write_u32(&mut bytes[17..21], self.key_header_size);
+        BigEndian::write_u32(&mut bytes[21..25], self.value_header_size);
+        BigEndian::write_u32(&mut bytes[25..29], self.max_key_size);
+        BigEndian::write_u64(&mut bytes[29..37], self.section_size);
+        BigEndian::write_u64(&mut bytes[37..45], self.buffer_size);
+        let crc = crc32fast::hash(&bytes[12..]);
+        BigEndian::write_u32(&mut bytes[8..12], crc);
+        bytes
+    }
+
+    /// Parse a superblock, `Err` describes why it is not one
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < SUPERBLOCK_SIZE as usize || &bytes[0..8] != MAGIC {
+            return Err("not a toy-kv file".to_owned());
+        }
+        let bytes = &bytes[..SUPERBLOCK_SIZE as usize];
+        if BigEndian::read_u32(&bytes[8..12]) != crc32fast::hash(&bytes[12..]) {
+            return Err("corrupted superblock".to_owned());
+        }
+        let version = BigEndian::read_u32(&bytes[12..16]);
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported format version {}, expected {}",
+                version, FORMAT_VERSION
+            ));
+        }
+        let kind = FileKind::from_tag(bytes[16])
+            .ok_or_else(|| format!("unknown file kind {:#x}", bytes[16]))?;
+        Ok(Superblock {
+            kind,
+            version,
+            key_header_size: BigEndian::read_u32(&bytes[17..21]),
+            value_header_size: BigEndian::read_u32(&bytes[21..25]),
+            max_key_size: BigEndian::read_u32(&bytes[25..29]),
+            section_size: BigEndian::read_u64(&bytes[29..37]),
+            buffer_size: BigEndian::read_u64(&bytes[37..45]),
+        })
+    }
+
+    /// Check that a file with this superblock can be read as `expected`
+    /// Returns why it can not
+    pub fn check(&self, expected: &Superblock) -> Result<(), String> {
+        if self.kind != expected.kind {
+            return Err(format!(
+                "{} file, expected a {} file",
+                self.kind.name(),
+                expected.kind.name()
+            ));
+        }
+        let geometry = [
+            (
+                "key header size",
+                u64::from(self.key_header_size),
+                u64::from(expected.key_header_size),
+            ),
+            (
+                "value header size",
+                u64::from(self.value_header_size),
+                u64::from(expected.value_header_size),
+            ),
+            (
+                "max key size",
+                u64::from(self.max_key_size),
+                u64::from(expected.max_key_size),
+            ),
+            ("section size", self.section_size, expected.section_size),
+            ("buffer size", self.buffer_size, expected.buffer_size),
+        ];
+        for (name, found, wanted) in geometry.iter() {
+            if found != wanted {
+                return Err(format!("{} {}, expected {}", name, found, wanted));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the superblock of `path`
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Superblock, Error> {
+    Superblock::from_bytes(&read_head(&path)?).map_err(|reason| Error::InvalidSuperblock {
+        file: path.as_ref().to_path_buf(),
+        reason,
+    })
+}
+
+/// Read and check the superblock of `path`
+pub fn check<P: AsRef<Path>>(
+    path: P,
+    kind: FileKind,
+    options: &StoreOptions,
+) -> Result<Superblock, Error> {
+    let superblock = read(&path)?;
+    superblock
+        .check(&Superblock::new(kind, options))
+        .map_err(|reason| Error::InvalidSuperblock {
+            file: path.as_ref().to_path_buf(),
+            reason,
+        })?;
+    Ok(superblock)
+}
+
+/// Write the superblock of a new file
+pub fn write<W: Write>(
+    writer: &mut W,
+    kind: FileKind,
+    options: &StoreOptions,
+) -> Result<(), Error> {
+    writer.write_all(&Superblock::new(kind, options).to_bytes())?;
+    Ok(())
+}
+
+/// Prepare the db files of a store:
+/// files created by this version get a superblock with the geometry of `options`,
+/// a store written before superblocks is migrated(see `util::migrate_baseline`),
+/// then every superblock is checked.
+/// Returns `options` with the geometry the store was created with.
+pub fn prepare<P: AsRef<Path>>(
+    key_file: P,
+    value_file: P,
+    buffer_file: P,
+    options: &StoreOptions,
+) -> Result<StoreOptions, Error> {
+    let files = [
+        (value_file.as_ref(), FileKind::Values),
+        (buffer_file.as_ref(), FileKind::Buffer),
+        (key_file.as_ref(), FileKind::Keys),
+    ];
+    if is_new(key_file.as_ref())? {
+        // The keys file last, its superblock tells the store is created
+        for (path, kind) in files.iter() {
+            if is_new(path)? {
+                let mut f = File::create(path)?;
+                write(&mut f, *kind, options)?;
+                f.sync_all()?;
+            }
+        }
+        util::sync_dir(&key_file)?;
+    } else if is_baseline(key_file.as_ref(), buffer_file.as_ref())? {
+        util::migrate_baseline(&key_file, &value_file, &buffer_file, options)?;
+    } else {
+        // A migration crashed after its commit, before renaming the new buffer file
+        let buffer_tmp = util::with_suffix(&buffer_file, util::COMPACT_SUFFIX);
+        if buffer_tmp.exists() {
+            fs::rename(&buffer_tmp, &buffer_file)?;
+            util::sync_dir(&buffer_file)?;
+        }
+    }
+    let key_superblock = read(&key_file)?;
+    let options = StoreOptions {
+        section_size: key_superblock.section_size as usize,
+        buffer_size: key_superblock.buffer_size as usize,
+        ..*options
+    };
+    for (path, kind) in files.iter() {
+        check(path, *kind, &options)?;
+    }
+    Ok(options)
+}
+
+/// Whether the file is missing or empty
+fn is_new(path: &Path) -> Result<bool, Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len() == 0),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether the store was written before superblocks, see `kv::BASELINE_*`:
+/// the keys file holds whole sections starting with the record of ventry 0
+/// (or none at all), the buffer file has the size of that version's buffer.
+fn is_baseline(key_file: &Path, buffer_file: &Path) -> Result<bool, Error> {
+    let head = read_head(key_file)?;
+    if head.starts_with(MAGIC)
+        || util::get_file_size(key_file)? % BASELINE_KEY_FILE_SIZE as u64 != 0
+        || util::get_file_size(buffer_file).ok() != Some(BASELINE_BUFFER_SIZE as u64)
+        || read_head(buffer_file)?.starts_with(MAGIC)
+    {
+        return Ok(false);
+    }
+    Ok(match baseline_key_from_bytes(&head) {
+        Some((_, ventry)) => ventry == 0,
+        None => true,
+    })
+}
+
+/// The first `SUPERBLOCK_SIZE` bytes of a file, fewer if it is shorter
+fn read_head<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let mut head = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+    File::open(path)?
+        .take(SUPERBLOCK_SIZE)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn superblock_test() {
+        let options = StoreOptions::default();
+        let superblock = Superblock::new(FileKind::Values, &options);
+        let mut bytes = superblock.to_bytes();
+        assert_eq!(bytes.len(), SUPERBLOCK_SIZE as usize);
+        assert_eq!(Superblock::from_bytes(&bytes).unwrap(), superblock);
+        assert!(superblock
+            .check(&Superblock::new(FileKind::Values, &options))
+            .is_ok());
+        assert_eq!(
+            superblock.check(&Superblock::new(FileKind::Keys, &options)),
+            Err("values file, expected a keys file".to_owned())
+        );
+        let other = Superblock {
+            buffer_size: 1024,
+            ..Superblock::new(FileKind::Values, &options)
+        };
+        assert_eq!(
+            Superblock::from_bytes(&other.to_bytes())
+                .unwrap()
+                .check(&superblock),
+            Err(format!("buffer size 1024, expected {}", BUFFER_SIZE))
+        );
+
+        bytes[40] ^= 1;
+        assert_eq!(
+            Superblock::from_bytes(&bytes),
+            Err("corrupted superblock".to_owned())
+        );
+        assert_eq!(
+            Superblock::from_bytes(&[0; SUPERBLOCK_SIZE as usize]),
+            Err("not a toy-kv file".to_owned())
+        );
+        let future = Superblock {
+            version: FORMAT_VERSION + 1,
+            ..Superblock::new(FileKind::Keys, &options)
+        };
+        assert_eq!(
+            Superblock::from_bytes(&future.to_bytes()),
+            Err(format!(
+                "unsupported format version {}, expected {}",
+                FORMAT_VERSION + 1,
+                FORMAT_VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn prepare_baseline_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (k, v, b) = (
+            dir.path().join("toy.k"),
+            dir.path().join("toy.v"),
+            dir.path().join("toy.b"),
+        );
+        let options = StoreOptions::default();
+        // An empty store of the layout before superblocks
+        fs::write(&k, vec![0; BASELINE_KEY_FILE_SIZE]).unwrap();
+        fs::write(&v, vec![0; BASELINE_VALUE_SIZE * 65536]).unwrap();
+        fs::write(&b, vec![0; BASELINE_BUFFER_SIZE]).unwrap();
+        assert!(is_baseline(&k, &b).unwrap());
+        // Its first record has ventry 0
+        let mut keys = vec![0; BASELINE_KEY_FILE_SIZE];
+        keys[..BASELINE_KEY_SIZE].copy_from_slice(b"key\0\0\0\0\0\0\0\0\x05");
+        fs::write(&k, &keys).unwrap();
+        assert!(!is_baseline(&k, &b).unwrap());
+        keys[BASELINE_KEY_SIZE - 1] = 0;
+        fs::write(&k, &keys).unwrap();
+        assert!(is_baseline(&k, &b).unwrap());
+        fs::write(&b, vec![0; 4096]).unwrap();
+        assert!(!is_baseline(&k, &b).unwrap());
+        fs::write(&b, vec![0; BASELINE_BUFFER_SIZE]).unwrap();
+
+        prepare(&k, &v, &b, &options).unwrap();
+        assert!(!is_baseline(&k, &b).unwrap());
+        for (path, kind) in &[
+            (&k, FileKind::Keys),
+            (&v, FileKind::Values),
+            (&b, FileKind::Buffer),
+        ] {
+            assert!(check(path, *kind, &options).is_ok());
+        }
+
+        // A migration which crashed before renaming the new buffer file
+        let buffer = fs::read(&b).unwrap();
+        fs::write(util::with_suffix(&b, util::COMPACT_SUFFIX), &buffer).unwrap();
+        fs::write(&b, vec![0; BASELINE_BUFFER_SIZE]).unwrap();
+        prepare(&k, &v, &b, &options).unwrap();
+        assert_eq!(fs::read(&b).unwrap(), buffer);
+        assert!(!util::with_suffix(&b, util::COMPACT_SUFFIX).exists());
+    }
+}
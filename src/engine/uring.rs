@@ -0,0 +1,152 @@
+/// A shared io_uring instance
+/// Operations are tagged with an id when submitted, and waited by it.
+/// Completions reaped while waiting for another id are kept for their waiters.
+use io_uring::{squeue, IoUring};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Condvar, Mutex};
+
+/// A failed `Ring::submit`
+pub struct SubmitError {
+    pub error: io::Error,
+    /// Whether the operations already pushed have completed,
+    /// their buffers must be leaked otherwise
+    pub settled: bool,
+}
+
+/// A failed `Ring::wait`
+pub struct WaitError {
+    pub error: io::Error,
+    /// Whether the operation has completed(and failed),
+    /// its buffer must be kept or leaked otherwise
+    pub reaped: bool,
+}
+
+/// The queues are only touched with `state` held, waiting in the kernel is not:
+/// one waiter at a time blocks there, the others wait for it to reap the completions.
+pub struct Ring {
+    ring: IoUring,
+    state: Mutex<State>,
+    // Notified once a waiter is back from the kernel
+    reaped: Condvar,
+}
+
+struct State {
+    next_id: u64,
+    // id -> result of completed operations not waited yet
+    done: HashMap<u64, i32>,
+    // Whether a waiter is blocked in the kernel
+    waiting: bool,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Self> {
+        Ok(Ring {
+            ring: IoUring::new(entries)?,
+            state: Mutex::new(State {
+                next_id: 0,
+                done: HashMap::new(),
+                waiting: false,
+            }),
+            reaped: Condvar::new(),
+        })
+    }
+
+    fn push(&self, state: &mut State, entry: squeue::Entry) -> io::Result<u64> {
+        let id = state.next_id;
+        state.next_id += 1;
+        let entry = entry.user_data(id);
+        loop {
+            // The submission queue is only pushed with `state` held
+            if unsafe { self.ring.submission_shared().push(&entry) }.is_ok() {
+                return Ok(id);
+            }
+            // Submission queue is full, hand it to the kernel
+            self.ring.submit()?;
+            self.reap(state);
+        }
+    }
+
+    fn reap(&self, state: &mut State) {
+        // The completion queue is only read with `state` held
+        for cqe in unsafe { self.ring.completion_shared() } {
+            state.done.insert(cqe.user_data(), cqe.result());
+        }
+    }
+
+    /// Submit operations without waiting for them
+    /// Returns their ids
+    ///
+    /// # Safety
+    /// Buffers of the operations must stay valid until they are waited.
+    /// On error, they must also stay valid(ie leaked) unless the error tells
+    /// the operations already pushed have settled.
+    pub unsafe fn submit(&self, entries: Vec<squeue::Entry>) -> Result<Vec<u64>, SubmitError> {
+        let (error, ids) = {
+            let mut state = self.state.lock().unwrap();
+            let mut ids = Vec::with_capacity(entries.len());
+            let mut result = Ok(());
+            for entry in entries {
+                match self.push(&mut state, entry) {
+                    Ok(id) => ids.push(id),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            if let Ok(()) = result {
+                result = self.ring.submit().map(|_| ());
+            }
+            match result {
+                Ok(()) => return Ok(ids),
+                Err(error) => (error, ids),
+            }
+        };
+        // The kernel may still use the buffers of the operations pushed
+        let settled = ids
+            .into_iter()
+            .all(|id| !matches!(self.wait(id), Err(WaitError { reaped: false, .. })));
+        Err(SubmitError { error, settled })
+    }
+
+    /// Wait for the operation `id`
+    /// Returns its result, ie bytes read or written.
+    /// Fails with `reaped: false` if the ring fails first, the operation may still be in flight then.
+    pub fn wait(&self, id: u64) -> Result<usize, WaitError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(res) = state.done.remove(&id) {
+                if res < 0 {
+                    return Err(WaitError {
+                        error: io::Error::from_raw_os_error(-res),
+                        reaped: true,
+                    });
+                }
+                return Ok(res as usize);
+            }
+            if state.waiting {
+                state = self.reaped.wait(state).unwrap();
+                continue;
+            }
+            state.waiting = true;
+            drop(state);
+            let result = self.ring.submit_and_wait(1);
+            state = self.state.lock().unwrap();
+            state.waiting = false;
+            self.reap(&mut state);
+            self.reaped.notify_all();
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) if !state.done.contains_key(&id) => {
+                    return Err(WaitError {
+                        error,
+                        reaped: false,
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,10 +1,26 @@
 use super::error::*;
 use super::kv::*;
+use super::options::StoreOptions;
+use super::superblock::{self, FileKind, SUPERBLOCK_SIZE};
 
+use byteorder::{BigEndian, ByteOrder};
 use memmap::{MmapMut, MmapOptions};
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Suffix of the files being written by a compaction
+pub const COMPACT_SUFFIX: &str = ".compact";
+/// Suffix of the keys file of a finished compaction
+pub const COMPACTED_SUFFIX: &str = ".compacted";
+/// Suffix of the index checkpoint of a keys file
+pub const INDEX_SUFFIX: &str = ".idx";
+/// Lock file of a store directory
+pub const LOCK_FILE: &str = "LOCK";
 
 /// Binary search
 /// Given an `InnerKey`
@@ -24,6 +40,9 @@ pub fn bsearch(index: &[Key], key: &InnerKey) -> Option<usize> {
         if &index[mid].inner < key {
             left = mid + 1;
         } else if &index[mid].inner > key {
+            if mid == 0 {
+                break;
+            }
             right = mid - 1;
         } else {
             while mid < index.len() - 1 {
@@ -83,6 +102,62 @@ pub fn find_insert_point(index: &[Key], key: &InnerKey) -> (bool, usize) {
     (false, mid)
 }
 
+/// Given the start bound of a scan
+/// Returns the position in the index vector to scan from
+pub fn seek(index: &[Key], start: Bound<&InnerKey>) -> usize {
+    match start {
+        Bound::Unbounded => 0,
+        Bound::Included(key) => lower_bound(index, key),
+        Bound::Excluded(key) => find_insert_point(index, key).1,
+    }
+}
+
+/// Position of the first version of the key,
+/// or where the key would be inserted
+fn lower_bound(index: &[Key], key: &InnerKey) -> usize {
+    match bsearch(index, key) {
+        Some(mut pos) => {
+            while pos > 0 && &index[pos - 1].inner == key {
+                pos -= 1;
+            }
+            pos
+        }
+        None => find_insert_point(index, key).1,
+    }
+}
+
+/// Given the end bound of a scan
+/// Returns the position in the index vector to scan to(exclusive)
+pub fn seek_back(index: &[Key], end: Bound<&InnerKey>) -> usize {
+    match end {
+        Bound::Unbounded => index.len(),
+        Bound::Included(key) => find_insert_point(index, key).1,
+        Bound::Excluded(key) => lower_bound(index, key),
+    }
+}
+
+/// Given a prefix
+/// Returns the end bound of keys starting with it,
+/// the prefix with its last byte(not 0xff) increased
+pub fn prefix_end(prefix: &[u8]) -> Bound<InnerKey> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(InnerKey { raw: end });
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Current unix time in milliseconds
+pub fn now_millis() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch");
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
 /// Get File with rw permission
 pub fn get_rw_fd<P: AsRef<Path>>(file: P) -> File {
     OpenOptions::new()
@@ -92,60 +167,115 @@ pub fn get_rw_fd<P: AsRef<Path>>(file: P) -> File {
         .unwrap_or_else(|_| panic!("failed to open file: {:?}", file.as_ref()))
 }
 
+/// Take an exclusive advisory lock(flock) on the lock file of `dir`
+/// Returns `Error::Locked` if another store holds it.
+/// The lock is released once the returned file is closed.
+pub fn lock_dir<P: AsRef<Path>>(dir: P) -> Result<File, Error> {
+    let path = dir.as_ref().join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    flock(file, path)
+}
+
+/// Like `lock_dir`, without creating the lock file: for tools that do not change `dir`
+/// Returns `None` if there is no lock file, the directory was never opened by a store.
+pub fn lock_existing_dir<P: AsRef<Path>>(dir: P) -> Result<Option<File>, Error> {
+    let path = dir.as_ref().join(LOCK_FILE);
+    match File::open(&path) {
+        Ok(file) => flock(file, path).map(Some),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn flock(file: File, path: PathBuf) -> Result<File, Error> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(Error::Locked { file: path });
+        }
+        return Err(err.into());
+    }
+    Ok(file)
+}
+
+/// Size of a memory page, mappings start on one
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// `size` bytes of a file mapped at `offset`, see `get_rw_mmap_fd`
+/// The mapping starts at the page holding `offset`, the bytes before it are not exposed.
+pub struct MappedRegion {
+    mmap: MmapMut,
+    skip: usize,
+}
+
+impl MappedRegion {
+    /// Flush the region to the file
+    pub fn flush(&self) -> io::Result<()> {
+        self.mmap.flush_range(self.skip, self.len())
+    }
+}
+
+impl Deref for MappedRegion {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mmap[self.skip..]
+    }
+}
+
+impl DerefMut for MappedRegion {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.mmap[self.skip..]
+    }
+}
+
 /// Get the mutable memmap handle
-pub fn get_rw_mmap_fd<P: AsRef<Path>>(file: P, size: usize, offset: u64) -> MmapMut {
+/// `offset` needs not be on a page, ie right after the 4kb superblock with 16kb or 64kb pages.
+pub fn get_rw_mmap_fd<P: AsRef<Path>>(file: P, size: usize, offset: u64) -> MappedRegion {
     let fd = get_rw_fd(file.as_ref());
-    unsafe {
+    let skip = (offset % page_size() as u64) as usize;
+    let mmap = unsafe {
         MmapOptions::new()
-            .len(size)
-            .offset(offset)
+            .len(skip + size)
+            .offset(offset - skip as u64)
             .map_mut(&fd)
             .unwrap_or_else(|_| panic!("failed to mmap file: {:?}", file.as_ref()))
-    }
+    };
+    MappedRegion { mmap, skip }
 }
 
 /// Build index from keys file
-/// step 1, load keys from &[u8]
+/// step 1, load keys from &[u8], section by section
 /// step 2, multi-level sort keys by key and ventry number
 /// for exmaple:
-/// ```rust
+/// ```text
 /// [
-///    // KEY( 8 bytes)       + ventry( 4 bytes)
-///    2, 1, 1, 1, 1, 1, 1, 1,  0, 0, 0, 0, // the first record
-///    1, 1, 1, 1, 1, 1, 1, 2,  0, 0, 0, 1, // the second record
-///    1, 1, 1, 1, 1, 1, 1, 3,  0, 0, 0, 2, // the third record
-///    2, 1, 1, 1, 1, 1, 1, 1,  0, 0, 0, 3, // the fourth record
+///    // CRC + FLAGS + KEY LEN + VALUE LEN + VENTRY + VALUE POINTER + KEY
+///    crc,    0,      2,        1,          0,       0,              [2, 1], // the first record
+///    crc,    0,      2,        1,          1,       9,              [1, 2], // the second record
+///    crc,    0,      2,        1,          2,       18,             [1, 3], // the third record
+///    crc,    0,      2,        1,          3,       27,             [2, 1], // the fourth record
 /// ];
 /// // ventries should be ordered as: [1, 2, 0, 3]
 /// ```
-pub fn build_index<P: AsRef<Path>>(path: P, start: u64, end: u64) -> Result<Vec<Key>, Error> {
-    if (end - start) % KEY_FILE_SIZE as u64 != 0 {
+/// A record failing the crc check is reported as `Error::Corrupted`
+/// Records of a batch are only loaded once its `FLAG_BATCH_END` record is found
+pub fn build_index<P: AsRef<Path>>(
+    path: P,
+    start: u64,
+    end: u64,
+    section_size: usize,
+) -> Result<Vec<Key>, Error> {
+    if !(end - start).is_multiple_of(section_size as u64) {
         return Err(Error::WrongAlignment);
     }
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut v = Vec::new();
-    for pos in (start..end).step_by(KEY_FILE_SIZE) {
-        // For each chunk
-        let mut mkey = vec![0; KEY_FILE_SIZE];
-        reader.seek(SeekFrom::Start(pos as u64))?;
-        reader.read_exact(&mut mkey)?;
-        for x in (0..mkey.len()).step_by(MKEY_SIZE) {
-            let chunk = &mkey[x..x + MKEY_SIZE];
-            if chunk == [0; MKEY_SIZE] {
-                break;
-            }
-            let mut inner_key = InnerKey { raw: [0; KEY_SIZE] };
-            inner_key.raw.clone_from_slice(&chunk[0..KEY_SIZE]);
-            v.push(Key {
-                inner: inner_key,
-                ventry: (chunk[KEY_SIZE] as usize) << 24
-                    | (chunk[KEY_SIZE + 1] as usize) << 16
-                    | (chunk[KEY_SIZE + 2] as usize) << 8
-                    | chunk[KEY_SIZE + 3] as usize,
-            });
-        }
-    }
+    let mut v = replay_keys(path, start, end, section_size)?;
 
     // Multi-Level sort by [(inner, asc), (ventry. asc)]
     v.sort_by(|a, b| {
@@ -158,77 +288,417 @@ pub fn build_index<P: AsRef<Path>>(path: P, start: u64, end: u64) -> Result<Vec<
     Ok(v)
 }
 
-pub fn get_buffer_pos(buffer: &[u8]) -> Result<u64, Error> {
-    if buffer.len() % VALUE_SIZE != 0 {
-        return Err(Error::WrongAlignment);
+/// Load key records from `from` to `end`(both may be in the middle of a section),
+/// in the order they were written. Positions are counted after the superblock.
+pub fn replay_keys<P: AsRef<Path>>(
+    path: P,
+    from: u64,
+    end: u64,
+    section_size: usize,
+) -> Result<Vec<Key>, Error> {
+    let file = File::open(&path)?;
+    let mut reader = BufReader::new(file);
+    let mut v = Vec::new();
+    // Records of a batch not committed yet
+    let mut batch: Vec<Key> = Vec::new();
+    let start = from - from % section_size as u64;
+    for pos in (start..end).step_by(section_size) {
+        // For each section, the last one only up to `end`
+        let mut section = vec![0; section_size.min((end - pos) as usize)];
+        reader.seek(SeekFrom::Start(SUPERBLOCK_SIZE + pos))?;
+        reader.read_exact(&mut section)?;
+        let mut x = if pos == start {
+            (from - start) as usize
+        } else {
+            0
+        };
+        while let Some(key) = key_from_bytes(&section[x..]).map_err(|_| Error::Corrupted {
+            file: path.as_ref().to_path_buf(),
+            offset: SUPERBLOCK_SIZE + pos + x as u64,
+        })? {
+            x += key_record_size(key.inner.raw.len());
+            if key.flags & FLAG_BATCH == 0 {
+                // A batch interrupted by a plain write was never committed
+                batch.clear();
+                v.push(key);
+                continue;
+            }
+            // A batch reusing the ventries of a discarded one starts a new run
+            match batch.last() {
+                Some(last) if key.ventry <= last.ventry => batch.clear(),
+                _ => {}
+            }
+            let end = key.flags & FLAG_BATCH_END != 0;
+            batch.push(key);
+            if end {
+                v.append(&mut batch);
+            }
+        }
     }
-    let mut pos = 0;
-    while pos < buffer.len() {
-        let chunk = &buffer[pos..pos + VALUE_SIZE];
-        if chunk[..] == [0; VALUE_SIZE][..] {
-            break;
+    Ok(v)
+}
+
+/// Index checkpoint header: crc(4 bytes) + keys file offset(8 bytes)
+/// + next ventry(8 bytes) + number of keys(8 bytes)
+const CHECKPOINT_HEADER_SIZE: usize = 28;
+
+/// Checkpoint of the index, loaded instead of the keys before `key_end`
+pub struct IndexCheckpoint {
+    /// offset in the keys file right after the last checkpointed record
+    pub key_end: u64,
+    /// ventry of the next record
+    pub ventry: u64,
+    /// keys sorted by (inner, ventry)
+    pub keys: Vec<Key>,
+}
+
+/// Writes the index checkpoint: [header][key records][crc(4 bytes)]
+/// The crc of the header covers the rest of it, the last one covers the records.
+/// Streamed to a tmp file then renamed once the header is written.
+pub struct IndexWriter {
+    writer: io::BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    path: PathBuf,
+    tmp: PathBuf,
+    key_end: u64,
+    ventry: u64,
+    count: u64,
+}
+
+impl IndexWriter {
+    /// Start the checkpoint of the records before `key_end`, whose ventries are below `ventry`
+    pub fn create<P: AsRef<Path>>(path: P, key_end: u64, ventry: u64) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let tmp = with_suffix(&path, COMPACT_SUFFIX);
+        let mut writer = io::BufWriter::new(File::create(&tmp)?);
+        // The header is written once the records are
+        writer.write_all(&[0u8; CHECKPOINT_HEADER_SIZE])?;
+        Ok(IndexWriter {
+            writer,
+            hasher: crc32fast::Hasher::new(),
+            path,
+            tmp,
+            key_end,
+            ventry,
+            count: 0,
+        })
+    }
+
+    /// Append a key, sorted by (inner, ventry)
+    pub fn append(&mut self, key: &Key) -> Result<(), Error> {
+        let bytes = key_to_bytes(key);
+        self.hasher.update(&bytes);
+        self.writer.write_all(&bytes)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Write the crc of the records and the header, then rename the checkpoint into place
+    pub fn finish(mut self) -> Result<(), Error> {
+        let crc = self.hasher.finalize();
+        self.writer.write_all(&crc.to_be_bytes())?;
+        let mut header = [0u8; CHECKPOINT_HEADER_SIZE];
+        BigEndian::write_u64(&mut header[4..12], self.key_end);
+        BigEndian::write_u64(&mut header[12..20], self.ventry);
+        BigEndian::write_u64(&mut header[20..CHECKPOINT_HEADER_SIZE], self.count);
+        let crc = crc32fast::hash(&header[4..]);
+        BigEndian::write_u32(&mut header[0..4], crc);
+
+        let mut f = self.writer.into_inner().map_err(|e| e.into_error())?;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&header)?;
+        f.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Read the header of the index checkpoint: the keys file offset it covers and the next ventry
+/// Returns `None` if it is missing or broken, the records are not checked.
+pub fn load_index_header<P: AsRef<Path>>(path: P) -> Result<Option<(u64, u64)>, Error> {
+    let mut header = Vec::with_capacity(CHECKPOINT_HEADER_SIZE);
+    match File::open(&path) {
+        Ok(f) => f
+            .take(CHECKPOINT_HEADER_SIZE as u64)
+            .read_to_end(&mut header)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if header.len() < CHECKPOINT_HEADER_SIZE
+        || BigEndian::read_u32(&header[0..4]) != crc32fast::hash(&header[4..])
+    {
+        return Ok(None);
+    }
+    Ok(Some((
+        BigEndian::read_u64(&header[4..12]),
+        BigEndian::read_u64(&header[12..20]),
+    )))
+}
+
+/// Load the index checkpoint, streamed
+/// Returns `None` if it is missing or broken, the index is rebuilt then
+pub fn load_index<P: AsRef<Path>>(path: P) -> Result<Option<IndexCheckpoint>, Error> {
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    // A short or broken file is not an error, only a checkpoint to ignore
+    let mut read = |buf: &mut [u8]| match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    };
+    let mut header = [0u8; CHECKPOINT_HEADER_SIZE];
+    if !read(&mut header)? || BigEndian::read_u32(&header[0..4]) != crc32fast::hash(&header[4..]) {
+        return Ok(None);
+    }
+    let key_end = BigEndian::read_u64(&header[4..12]);
+    let ventry = BigEndian::read_u64(&header[12..20]);
+    let count = BigEndian::read_u64(&header[20..CHECKPOINT_HEADER_SIZE]);
+
+    let mut hasher = crc32fast::Hasher::new();
+    let mut keys = Vec::new();
+    let mut record = vec![0u8; KEY_HEADER_SIZE];
+    for _ in 0..count {
+        record.resize(KEY_HEADER_SIZE, 0);
+        if !read(&mut record)? {
+            return Ok(None);
         }
-        pos += VALUE_SIZE;
+        let klen = BigEndian::read_u16(&record[5..7]) as usize;
+        if klen == 0 || klen > MAX_KEY_SIZE {
+            return Ok(None);
+        }
+        record.resize(key_record_size(klen), 0);
+        if !read(&mut record[KEY_HEADER_SIZE..])? {
+            return Ok(None);
+        }
+        match key_from_bytes(&record) {
+            Ok(Some(key)) => keys.push(key),
+            _ => return Ok(None),
+        }
+        hasher.update(&record);
+    }
+    let mut crc = [0u8; 4];
+    if !read(&mut crc)? || BigEndian::read_u32(&crc) != hasher.finalize() {
+        return Ok(None);
+    }
+    Ok(Some(IndexCheckpoint {
+        key_end,
+        ventry,
+        keys,
+    }))
+}
+
+/// Find the end position of the key records in a section of `path`, at `offset` of the file
+/// A record failing the crc check is reported as `Error::Corrupted`
+pub fn get_key_pos<P: AsRef<Path>>(path: P, offset: u64, section: &[u8]) -> Result<u64, Error> {
+    let mut pos = 0;
+    while let Some(key) = key_from_bytes(&section[pos..]).map_err(|_| Error::Corrupted {
+        file: path.as_ref().to_path_buf(),
+        offset: offset + pos as u64,
+    })? {
+        pos += key_record_size(key.inner.raw.len());
     }
     Ok(pos as u64)
 }
 
-pub fn ensure_size<P: AsRef<Path>>(path: P, chunk_size: u64, item_size: u64) -> Result<u64, Error> {
-    let metadata = fs::metadata(&path);
-    match metadata {
-        Err(_) => {
-            // Create
-            File::create(&path)?;
-            ensure_size(path, chunk_size, item_size)
+/// Make sure the file exists and holds at least `size` bytes
+/// Returns the length of the file
+pub fn ensure_size<P: AsRef<Path>>(path: P, size: u64) -> Result<u64, Error> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    let len = f.metadata()?.len();
+    if len < size {
+        f.set_len(size)?;
+        return Ok(size);
+    }
+    Ok(len)
+}
+
+/// Path of `path` with a suffix appended, ie `toy.k.compact`
+pub fn with_suffix<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Write `len` zeros
+pub fn pad<W: Write>(writer: &mut W, len: u64) -> Result<(), Error> {
+    io::copy(&mut io::repeat(0).take(len), writer)?;
+    Ok(())
+}
+
+/// Flush the renames in the directory of `path` to disk
+pub fn sync_dir<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let dir = match path.as_ref().parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Finish or roll back an interrupted compaction
+/// Renaming the new keys file to `*.compacted` is the commit point:
+/// before it, the new files are removed and the old ones are kept;
+/// after it, the new files replace the old ones, values file first.
+pub fn recover_compaction<P: AsRef<Path>>(key_file: P, value_file: P) -> Result<(), Error> {
+    let key_tmp = with_suffix(&key_file, COMPACT_SUFFIX);
+    let key_done = with_suffix(&key_file, COMPACTED_SUFFIX);
+    let value_tmp = with_suffix(&value_file, COMPACT_SUFFIX);
+    if key_done.exists() {
+        // The checkpoint indexes the old keys file
+        let index = with_suffix(&key_file, INDEX_SUFFIX);
+        if index.exists() {
+            fs::remove_file(index)?;
         }
-        Ok(meta) => {
-            let len = meta.len();
-            let f = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&path)?;
-            if len == 0 {
-                f.set_len(chunk_size)?;
-                Ok(0)
-            } else {
-                // Read last item
-                let mut reader = BufReader::new(&f);
-                reader.seek(SeekFrom::End(-(item_size as i64)))?;
-                let mut buf = vec![0; item_size as usize];
-                reader.read_exact(&mut buf)?;
-                if buf[..] != vec![0; item_size as usize][..] {
-                    // Full
-                    f.set_len(len + chunk_size)?;
-                    return Ok(len);
-                }
-                let pos = find_last_pos(&mut reader, len, chunk_size, item_size)?;
-                Ok(pos)
+        if value_tmp.exists() {
+            fs::rename(&value_tmp, &value_file)?;
+            sync_dir(&value_file)?;
+        }
+        fs::rename(&key_done, &key_file)?;
+        sync_dir(&key_file)?;
+    } else {
+        for tmp in &[key_tmp, value_tmp] {
+            if tmp.exists() {
+                fs::remove_file(tmp)?;
             }
         }
     }
+    Ok(())
 }
 
-/// Find the end position of content
-/// When data chunk is all 0 in continuous `item_size`, that's the end position
-fn find_last_pos<R: Read + Seek>(
-    reader: &mut R,
-    len: u64,
-    chunk_size: u64,
-    item_size: u64,
-) -> Result<u64, Error> {
-    let pos = len - chunk_size as u64;
-    reader.seek(SeekFrom::Start(pos))?;
-    let mut buf = vec![0; chunk_size as usize];
-    reader.read_exact(&mut buf)?;
-    // BUFFER_SIZE(4kb) is 16 times of VALUE_SIZE(256byte)
-    for i in (0..chunk_size as usize).step_by(item_size as usize) {
-        let chunk = &buf[i..i + item_size as usize];
-        if chunk[..] == vec![0; item_size as usize][..] {
-            return Ok(i as u64 + pos);
+/// Rewrite a store written before superblocks in the current format, see `kv::BASELINE_*`:
+/// every key record with its value, tombstones included, keeping the ventries.
+/// The values of the buffer file are moved to the values file, so the buffer starts empty.
+/// Written to tmp files then committed as a compaction(see `recover_compaction`),
+/// the new buffer file is renamed last, `superblock::prepare` finishes it after a crash.
+pub fn migrate_baseline<P: AsRef<Path>>(
+    key_file: P,
+    value_file: P,
+    buffer_file: P,
+    options: &StoreOptions,
+) -> Result<(), Error> {
+    let corrupted = |path: &Path, offset: u64| Error::Corrupted {
+        file: path.to_path_buf(),
+        offset,
+    };
+    let mut keys_in = Vec::new();
+    let mut reader = BufReader::new(File::open(&key_file)?);
+    let mut section = vec![0; BASELINE_KEY_FILE_SIZE];
+    for pos in (0..get_file_size(&key_file)?).step_by(BASELINE_KEY_FILE_SIZE) {
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_exact(&mut section)?;
+        let records = section.chunks(BASELINE_KEY_SIZE);
+        keys_in.extend(records.map_while(baseline_key_from_bytes));
+    }
+
+    // Slots of the buffer are the last ones written, the values file holds the others
+    let buffer = fs::read(&buffer_file)?;
+    let buffered = buffer
+        .chunks(BASELINE_VALUE_SIZE)
+        .take_while(|slot| slot.iter().any(|b| *b != 0))
+        .count();
+    let flushed = (keys_in.len().saturating_sub(buffered) * BASELINE_VALUE_SIZE) as u64;
+    let values_in = File::open(&value_file)?;
+    let mut slot = vec![0; BASELINE_VALUE_SIZE];
+
+    let key_tmp = with_suffix(&key_file, COMPACT_SUFFIX);
+    let value_tmp = with_suffix(&value_file, COMPACT_SUFFIX);
+    let buffer_tmp = with_suffix(&buffer_file, COMPACT_SUFFIX);
+    {
+        let mut keys = io::BufWriter::new(File::create(&key_tmp)?);
+        let mut values = io::BufWriter::new(File::create(&value_tmp)?);
+        superblock::write(&mut keys, FileKind::Keys, options)?;
+        superblock::write(&mut values, FileKind::Values, options)?;
+        let (section_size, buffer_size) = (options.section_size, options.buffer_size);
+        let (mut key_pos, mut value_pos) = (0, 0);
+
+        for (inner, ventry) in keys_in {
+            let offset = ventry * BASELINE_VALUE_SIZE as u64;
+            if offset >= flushed {
+                let start = (offset - flushed) as usize;
+                let bytes = buffer
+                    .get(start..start + BASELINE_VALUE_SIZE)
+                    .ok_or_else(|| corrupted(buffer_file.as_ref(), start as u64))?;
+                slot.copy_from_slice(bytes);
+            } else {
+                values_in
+                    .read_exact_at(&mut slot, offset)
+                    .map_err(|_| corrupted(value_file.as_ref(), offset))?;
+            }
+            let value = baseline_value_from_bytes(&slot);
+
+            // Records never span two buffers
+            let vbytes = value_to_bytes(&value);
+            let rest = buffer_size - value_pos % buffer_size;
+            if vbytes.len() > rest {
+                pad(&mut values, rest as u64)?;
+                value_pos += rest;
+            }
+            let key = Key {
+                inner,
+                ventry,
+                vptr: value_pos as u64,
+                vlen: value.vlen(),
+                flags: 0,
+                expires: 0,
+            };
+            values.write_all(&vbytes)?;
+            value_pos += vbytes.len();
+
+            // Nor two sections of keys
+            let kbytes = key_to_bytes(&key);
+            let rest = section_size - key_pos % section_size;
+            if kbytes.len() > rest {
+                pad(&mut keys, rest as u64)?;
+                key_pos += rest;
+            }
+            keys.write_all(&kbytes)?;
+            key_pos += kbytes.len();
         }
+
+        // Values file holds whole buffers only, so the buffer starts empty
+        let rest = (buffer_size - value_pos % buffer_size) % buffer_size;
+        pad(&mut values, rest as u64)?;
+        let rest = section_size - key_pos % section_size;
+        if key_pos == 0 || rest < section_size {
+            pad(&mut keys, rest as u64)?;
+        }
+        values
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        keys.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let mut buffer = io::BufWriter::new(File::create(&buffer_tmp)?);
+        superblock::write(&mut buffer, FileKind::Buffer, options)?;
+        pad(&mut buffer, buffer_size as u64)?;
+        buffer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
     }
-    unreachable!();
+
+    // Commit
+    fs::rename(&key_tmp, with_suffix(&key_file, COMPACTED_SUFFIX))?;
+    sync_dir(&key_file)?;
+    recover_compaction(&key_file, &value_file)?;
+    fs::rename(&buffer_tmp, &buffer_file)?;
+    sync_dir(&buffer_file)?;
+    Ok(())
+}
+
+/// Size of the data of a db file, after its superblock
+pub fn get_data_size<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+    Ok(get_file_size(path)?.saturating_sub(SUPERBLOCK_SIZE))
 }
 
 /// Simply returns the file size
@@ -254,13 +724,18 @@ mod util_tests {
                     Some(1),
                 ),
                 (vec!["key001", "key001", "key002", "key003"], "key004", None),
+                (vec!["key001", "key001", "key002", "key003"], "key000", None),
             ];
             for case in cases.iter() {
                 let mut index: Vec<Key> = Vec::new();
                 for i in 0..case.0.len() {
                     index.push(Key {
                         inner: case.0[i].parse().unwrap(),
-                        ventry: i,
+                        ventry: i as u64,
+                        vptr: 0,
+                        vlen: 0,
+                        flags: 0,
+                        expires: 0,
                     });
                 }
                 let result = bsearch(&index, &case.1.parse().unwrap());
@@ -288,7 +763,11 @@ mod util_tests {
                 for i in 0..case.0.len() {
                     index.push(Key {
                         inner: case.0[i].parse().unwrap(),
-                        ventry: i,
+                        ventry: i as u64,
+                        vptr: 0,
+                        vlen: 0,
+                        flags: 0,
+                        expires: 0,
                     });
                 }
                 let result = find_insert_point(&index, &case.1.parse().unwrap());
@@ -297,9 +776,104 @@ mod util_tests {
         }
     }
 
+    #[cfg(test)]
+    mod seek_tests {
+        use super::super::super::kv::*;
+        use super::super::{seek, seek_back};
+        use std::ops::Bound;
+
+        #[test]
+        fn seek_test() {
+            let index: Vec<Key> = ["key001", "key001", "key002", "key004"]
+                .iter()
+                .enumerate()
+                .map(|(ventry, raw)| Key {
+                    inner: raw.parse().unwrap(),
+                    ventry: ventry as u64,
+                    vptr: 0,
+                    vlen: 0,
+                    flags: 0,
+                    expires: 0,
+                })
+                .collect();
+            let key = |raw: &str| -> InnerKey { raw.parse().unwrap() };
+            let cases = [
+                (Bound::Unbounded, 0),
+                (Bound::Included(key("key000")), 0),
+                (Bound::Included(key("key001")), 0),
+                (Bound::Excluded(key("key001")), 2),
+                (Bound::Included(key("key003")), 3),
+                (Bound::Excluded(key("key003")), 3),
+                (Bound::Included(key("key004")), 3),
+                (Bound::Excluded(key("key004")), 4),
+                (Bound::Included(key("key005")), 4),
+            ];
+            for case in cases.iter() {
+                assert_eq!(seek(&index, case.0.as_ref()), case.1);
+            }
+        }
+
+        #[test]
+        fn seek_back_test() {
+            let index: Vec<Key> = ["key001", "key002", "key002", "key004"]
+                .iter()
+                .enumerate()
+                .map(|(ventry, raw)| Key {
+                    inner: raw.parse().unwrap(),
+                    ventry: ventry as u64,
+                    vptr: 0,
+                    vlen: 0,
+                    flags: 0,
+                    expires: 0,
+                })
+                .collect();
+            let key = |raw: &str| -> InnerKey { raw.parse().unwrap() };
+            let cases = [
+                (Bound::Unbounded, 4),
+                (Bound::Included(key("key000")), 0),
+                (Bound::Excluded(key("key001")), 0),
+                (Bound::Included(key("key001")), 1),
+                (Bound::Excluded(key("key002")), 1),
+                (Bound::Included(key("key002")), 3),
+                (Bound::Excluded(key("key003")), 3),
+                (Bound::Included(key("key003")), 3),
+                (Bound::Included(key("key004")), 4),
+                (Bound::Excluded(key("key005")), 4),
+            ];
+            for case in cases.iter() {
+                assert_eq!(seek_back(&index, case.0.as_ref()), case.1);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod prefix_end_tests {
+        use super::super::super::kv::*;
+        use super::super::prefix_end;
+        use std::ops::Bound;
+
+        #[test]
+        fn prefix_end_test() {
+            let cases: [(&[u8], Bound<&[u8]>); 5] = [
+                (b"", Bound::Unbounded),
+                (b"usr:", Bound::Excluded(b"usr;")),
+                (b"a\xff", Bound::Excluded(b"b")),
+                (b"a\xfe\xff\xff", Bound::Excluded(b"a\xff")),
+                (b"\xff\xff", Bound::Unbounded),
+            ];
+            for case in cases.iter() {
+                let expected = case.1.map(InnerKey::from);
+                assert_eq!(prefix_end(case.0), expected);
+            }
+        }
+    }
+
     #[cfg(test)]
     mod build_index_tests {
+        use super::super::super::error::*;
         use super::super::super::kv::*;
+        use super::super::super::options::StoreOptions;
+        use super::super::super::superblock::{self, FileKind, SUPERBLOCK_SIZE};
         use super::super::build_index;
 
         use std::fs::File;
@@ -319,52 +893,170 @@ mod util_tests {
         fn broken_test() {
             let tmp_path = tmp_path("broken_test");
             File::create(&tmp_path).unwrap();
-            let index = build_index(&tmp_path, 0, 11);
+            let index = build_index(&tmp_path, 0, 11, KEY_FILE_SIZE);
             assert!(index.is_err());
         }
 
         #[test]
         fn valid_test() {
-            let data = [
-                2, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, // the first record
-                1, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 1, // the second record
-                1, 1, 1, 1, 1, 1, 1, 3, 0, 0, 0, 2, // the third record
-                2, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 3, // the fourth record
-            ];
+            let data: Vec<u8> = [
+                (b"\x02\x01", 0),
+                (b"\x01\x02", 1),
+                (b"\x01\x03", 2),
+                (b"\x02\x01", 3),
+            ]
+            .iter()
+            .flat_map(|(raw, ventry)| {
+                key_to_bytes(&Key {
+                    inner: InnerKey::from(&raw[..]),
+                    ventry: *ventry,
+                    vptr: *ventry * 9,
+                    vlen: 1,
+                    flags: 0,
+                    expires: 0,
+                })
+            })
+            .collect();
             let tmp_path = tmp_path("broken_test");
             let mut f = File::create(&tmp_path).unwrap();
+            superblock::write(&mut f, FileKind::Keys, &StoreOptions::default()).unwrap();
             f.write(&data).unwrap();
             f.write(&vec![0; KEY_FILE_SIZE - data.len()]).unwrap();
-            let index = build_index(&tmp_path, 0, KEY_FILE_SIZE as u64).unwrap();
+            let index = build_index(&tmp_path, 0, KEY_FILE_SIZE as u64, KEY_FILE_SIZE).unwrap();
             // ventry should be ordered as: 1, 2, 0, 3
-            let entries: Vec<usize> = index.iter().map(|key| key.ventry).collect();
+            let entries: Vec<u64> = index.iter().map(|key| key.ventry).collect();
             assert_eq!(entries, [1, 2, 0, 3]);
         }
+
+        #[test]
+        fn corrupted_test() {
+            let mut data = key_to_bytes(&Key {
+                inner: InnerKey::from(&b"key"[..]),
+                ventry: 0,
+                vptr: 0,
+                vlen: 1,
+                flags: 0,
+                expires: 0,
+            });
+            let record_size = data.len();
+            data.extend_from_slice(&data.clone());
+            // Flip a bit of the second record
+            data[record_size + KEY_HEADER_SIZE] ^= 1;
+            let tmp_path = tmp_path("corrupted_test");
+            let mut f = File::create(&tmp_path).unwrap();
+            superblock::write(&mut f, FileKind::Keys, &StoreOptions::default()).unwrap();
+            f.write_all(&data).unwrap();
+            f.write_all(&vec![0; KEY_FILE_SIZE - data.len()]).unwrap();
+            let err = build_index(&tmp_path, 0, KEY_FILE_SIZE as u64, KEY_FILE_SIZE)
+                .err()
+                .unwrap();
+            assert_eq!(
+                err,
+                Error::Corrupted {
+                    file: tmp_path,
+                    offset: SUPERBLOCK_SIZE + record_size as u64
+                }
+            );
+        }
+
+        #[test]
+        fn batch_test() {
+            let data: Vec<u8> = [
+                // committed batch
+                (0, FLAG_BATCH),
+                (1, FLAG_BATCH | FLAG_BATCH_END),
+                // interrupted by a plain write
+                (2, FLAG_BATCH),
+                (2, 0),
+                // restarted over a discarded batch
+                (3, FLAG_BATCH),
+                (3, FLAG_BATCH),
+                (4, FLAG_BATCH | FLAG_BATCH_END),
+                // never committed
+                (5, FLAG_BATCH),
+            ]
+            .iter()
+            .flat_map(|(ventry, flags)| {
+                key_to_bytes(&Key {
+                    inner: InnerKey::from(format!("key{:03}", ventry).as_bytes()),
+                    ventry: *ventry,
+                    vptr: *ventry * 9,
+                    vlen: 1,
+                    flags: *flags,
+                    expires: 0,
+                })
+            })
+            .collect();
+            let tmp_path = tmp_path("batch_test");
+            let mut f = File::create(&tmp_path).unwrap();
+            superblock::write(&mut f, FileKind::Keys, &StoreOptions::default()).unwrap();
+            f.write_all(&data).unwrap();
+            f.write_all(&vec![0; KEY_FILE_SIZE - data.len()]).unwrap();
+            let index = build_index(&tmp_path, 0, KEY_FILE_SIZE as u64, KEY_FILE_SIZE).unwrap();
+            let entries: Vec<u64> = index.iter().map(|key| key.ventry).collect();
+            assert_eq!(entries, [0, 1, 2, 3, 4]);
+        }
     }
 
-    use super::super::error::*;
+    use super::super::error::Error;
     use super::super::kv::*;
-    use super::get_buffer_pos;
+    use super::get_key_pos;
+    use std::path::PathBuf;
     #[test]
-    fn get_buffer_pos_test() {
-        // Ok
+    fn get_key_pos_test() {
+        let key = Key {
+            inner: "key001".parse().unwrap(),
+            ventry: 0,
+            vptr: 0,
+            vlen: 7,
+            flags: 0,
+            expires: 0,
+        };
+        let record = key_to_bytes(&key);
         let cases = [
             (vec![], 0),
-            (vec![0; VALUE_SIZE], 0),
-            (vec![1; VALUE_SIZE], VALUE_SIZE),
+            (vec![0; KEY_FILE_SIZE], 0),
+            (
+                [&record[..], &vec![0; KEY_FILE_SIZE - record.len()]].concat(),
+                record.len(),
+            ),
             (
-                [&[1; VALUE_SIZE][..], &[0; VALUE_SIZE]].concat(),
-                VALUE_SIZE,
+                [&record[..], &record[..], &[0; 3]].concat(),
+                record.len() * 2,
             ),
         ];
 
         for case in &cases {
-            let result = get_buffer_pos(&case.0).unwrap();
+            let result = get_key_pos("toy.k", 0, &case.0).unwrap();
             assert_eq!(result, case.1 as u64);
         }
 
-        // Err
-        let err = get_buffer_pos(&vec![1]).err().unwrap();
-        assert_eq!(err, Error::WrongAlignment);
+        // A torn record is reported where it starts
+        let mut torn = [&record[..], &record[..], &[0; 3]].concat();
+        torn[record.len() * 2 - 1] ^= 0xff;
+        assert_eq!(
+            get_key_pos("toy.k", 4096, &torn).err().unwrap(),
+            Error::Corrupted {
+                file: PathBuf::from("toy.k"),
+                offset: 4096 + record.len() as u64,
+            }
+        );
+    }
+
+    #[test]
+    fn get_rw_mmap_fd_test() {
+        let path = tempfile::tempdir().unwrap().into_path().join("mmap");
+        std::fs::write(&path, vec![0u8; 3 * 4096]).unwrap();
+        // Neither offset is on a page, whatever its size
+        for &offset in &[4096 + 100, 100] {
+            let mut region = super::get_rw_mmap_fd(&path, 4096, offset);
+            assert_eq!(region.len(), 4096);
+            region[0] = 0xff;
+            region[4095] = 0xee;
+            region.flush().unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!(bytes[offset as usize], 0xff);
+            assert_eq!(bytes[offset as usize + 4095], 0xee);
+        }
     }
 }
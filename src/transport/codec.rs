@@ -2,6 +2,7 @@
 use actix::prelude::*;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
 use std::io;
@@ -12,12 +13,22 @@ use tokio_io::codec::{Decoder, Encoder};
 pub enum ToyRequest {
     /// Scan kv pairs
     Scan,
+    /// Scan kv pairs whose key starts with the prefix
+    ScanPrefix(String),
     /// Get the value of key
     Get(String),
+    /// Get the values of several keys
+    MGet(Vec<String>),
     /// Put kv pair
     Put((String, String)),
     /// Delte the value of key
     Delete(String),
+    /// Compare and swap: (key, expected, new), `None` for an absent key
+    Cas((String, Option<String>, Option<String>)),
+    /// Admin: checkpoint the store into a directory on the server, see `Store::checkpoint`
+    Checkpoint(String),
+    /// Admin: export the writes since a ventry into a delta file on the server, see `Store::export_delta`
+    Export((u64, String)),
     /// Ping
     Ping,
 }
@@ -28,14 +39,68 @@ pub enum ToyResponse {
     Ping,
     /// Value of key
     Value(String),
+    /// Values of several keys in requested order, `None` for an absent key
+    Values(Vec<(String, Option<String>)>),
     /// Saved key
     Saved((String, String)),
     /// Deleted key
     Deleted(String),
+    /// Key of a compare and swap, and whether it applied
+    Swapped((String, bool)),
+    /// Directory of a checkpoint and the ventry it was taken at
+    Checkpointed((String, u64)),
+    /// Delta file of an export and the ventry it ends at
+    Exported((String, u64)),
     /// Scan
     Next((String, String)),
 }
 
+/// Largest frame, a response of values up to `MAX_VALUE_SIZE` with their json escapes fits in it
+pub const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+/// Frame header: length of the json message(4 bytes)
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Write `msg` as a frame: [length(4 bytes)][json]
+fn encode_frame<T: Serialize>(msg: &T, dst: &mut BytesMut) -> Result<(), io::Error> {
+    let msg = json::to_vec(msg)?;
+    if msg.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {} bytes, at most {}", msg.len(), MAX_FRAME_SIZE),
+        ));
+    }
+
+    dst.reserve(msg.len() + FRAME_HEADER_SIZE);
+    dst.put_u32_be(msg.len() as u32);
+    dst.put(msg);
+
+    Ok(())
+}
+
+/// Read a frame once `src` holds all of it
+fn decode_frame<T: DeserializeOwned>(src: &mut BytesMut) -> Result<Option<T>, io::Error> {
+    let size = {
+        if src.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        BigEndian::read_u32(src.as_ref()) as usize
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes, at most {}", size, MAX_FRAME_SIZE),
+        ));
+    }
+
+    if src.len() >= size + FRAME_HEADER_SIZE {
+        src.split_to(FRAME_HEADER_SIZE);
+        let buf = src.split_to(size);
+        Ok(Some(json::from_slice::<T>(&buf)?))
+    } else {
+        Ok(None)
+    }
+}
+
 /// Codec for Client -> Server transport
 pub struct ToyServerCodec;
 
@@ -44,20 +109,7 @@ impl Decoder for ToyServerCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ToyRequest>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        decode_frame(src)
     }
 }
 
@@ -66,14 +118,7 @@ impl Encoder for ToyServerCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: ToyResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
-
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
-
-        Ok(())
+        encode_frame(&msg, dst)
     }
 }
 
@@ -85,20 +130,7 @@ impl Decoder for ToyClientCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ToyResponse>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        decode_frame(src)
     }
 }
 
@@ -107,13 +139,72 @@ impl Encoder for ToyClientCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: ToyRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        encode_frame(&msg, dst)
+    }
+}
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_large_value_test() {
+        let value = "v".repeat(70_000);
+        let mut buf = BytesMut::new();
+        ToyServerCodec
+            .encode(ToyResponse::Value(value.clone()), &mut buf)
+            .unwrap();
+        assert_eq!(
+            buf.len(),
+            FRAME_HEADER_SIZE + r#"{"Value":""}"#.len() + value.len()
+        );
+        // Not decoded until the whole frame is there
+        let mut head = buf.split_to(1024);
+        assert!(ToyClientCodec.decode(&mut head).unwrap().is_none());
+        head.unsplit(buf);
+        match ToyClientCodec.decode(&mut head).unwrap() {
+            Some(ToyResponse::Value(v)) => assert_eq!(v, value),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(head.is_empty());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_be(MAX_FRAME_SIZE as u32 + 1);
+        assert_eq!(
+            ToyServerCodec.decode(&mut buf).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn codec_mget_response_test() {
+        // Well over the 64kb a u16 length could frame
+        let values: Vec<(String, Option<String>)> = (0..200)
+            .map(|i| {
+                let value = if i % 10 == 0 {
+                    None
+                } else {
+                    Some(format!("{:04}", i).repeat(256))
+                };
+                (format!("key{:03}", i), value)
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        ToyServerCodec
+            .encode(ToyResponse::Values(values.clone()), &mut buf)
+            .unwrap();
+        ToyServerCodec
+            .encode(ToyResponse::Deleted("key".to_owned()), &mut buf)
+            .unwrap();
+        assert!(buf.len() > 128 * 1024);
+        match ToyClientCodec.decode(&mut buf).unwrap() {
+            Some(ToyResponse::Values(v)) => assert_eq!(v, values),
+            other => panic!("unexpected {:?}", other),
+        }
+        // The stream stays in sync
+        match ToyClientCodec.decode(&mut buf).unwrap() {
+            Some(ToyResponse::Deleted(key)) => assert_eq!(key, "key"),
+            other => panic!("unexpected {:?}", other),
+        }
     }
 }
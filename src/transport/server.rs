@@ -3,15 +3,23 @@
 //! room through `ToyServer`.
 
 use actix::prelude::*;
+use futures::sync::oneshot;
+use futures::Future;
 use rand::prelude::*;
 use std::collections::HashMap;
 
 use super::super::engine::error;
-use super::super::engine::kv;
-use super::super::engine::store::Store;
+use super::super::engine::store::{Store, StoreIter};
 use super::open_db_from;
 use super::session;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+/// Directory under the db directory that clients write backups to, unless configured
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
 
 /// New toy session is created
 pub struct Connect {
@@ -35,6 +43,14 @@ pub struct Disconnect {
 #[derive(Message)]
 pub struct Scan(pub usize);
 
+/// Scan kv pairs with prefix
+#[derive(Message)]
+pub struct ScanPrefix {
+    /// Client id
+    pub id: usize,
+    pub prefix: String,
+}
+
 /// Get value of key
 pub struct Get {
     /// Client id
@@ -47,6 +63,17 @@ impl actix::Message for Get {
     type Result = Result<String, error::Error>;
 }
 
+/// Get values of keys
+pub struct MGet {
+    /// Client id
+    pub id: usize,
+    pub keys: Vec<String>,
+}
+
+impl actix::Message for MGet {
+    type Result = Result<Vec<Option<String>>, error::Error>;
+}
+
 /// Put kv pair
 pub struct Put {
     /// Client id
@@ -70,20 +97,58 @@ impl actix::Message for Delete {
     type Result = Result<(), error::Error>;
 }
 
+/// Compare and swap the value of key
+pub struct Cas {
+    /// Client id
+    pub id: usize,
+    pub key: String,
+    pub expected: Option<String>,
+    pub new: Option<String>,
+}
+
+impl actix::Message for Cas {
+    type Result = Result<bool, error::Error>;
+}
+
+/// Checkpoint the store into a directory
+pub struct Checkpoint {
+    /// Client id
+    pub id: usize,
+    /// Relative to the backup root
+    pub dir: String,
+}
+
+impl actix::Message for Checkpoint {
+    type Result = Result<u64, error::Error>;
+}
+
+/// Export the writes since a ventry into a delta file
+pub struct Export {
+    /// Client id
+    pub id: usize,
+    pub since: u64,
+    /// Relative to the backup root
+    pub path: String,
+}
+
+impl actix::Message for Export {
+    type Result = Result<u64, error::Error>;
+}
+
 /// `ToyServer` manages toy rooms and responsible for coordinating toy
 /// session. implementation is super primitive
 pub struct ToyServer {
     sessions: HashMap<usize, Addr<session::ToySession>>,
-    store: Store,
+    // Shared with the threads copying checkpoints
+    store: Arc<Store>,
+    // Checkpoints and deltas requested by clients are written under it only
+    backup_root: PathBuf,
 }
 
 impl Default for ToyServer {
     fn default() -> ToyServer {
         let db_path: PathBuf = "toydb".parse().unwrap();
-        ToyServer {
-            sessions: HashMap::new(),
-            store: open_db_from(&db_path).unwrap(),
-        }
+        ToyServer::new(db_path)
     }
 }
 
@@ -91,9 +156,34 @@ impl ToyServer {
     pub fn new<P: AsRef<Path>>(db_path: P) -> ToyServer {
         ToyServer {
             sessions: HashMap::new(),
-            store: open_db_from(&db_path).unwrap(),
+            store: Arc::new(open_db_from(&db_path).unwrap()),
+            backup_root: db_path.as_ref().join(DEFAULT_BACKUP_DIR),
         }
     }
+
+    /// Write the backups requested by clients under `root`
+    pub fn with_backup_root<P: AsRef<Path>>(mut self, root: P) -> ToyServer {
+        self.backup_root = root.as_ref().to_path_buf();
+        self
+    }
+}
+
+/// Resolve `path`, sent by a client, under the backup `root`
+/// Only relative paths made of names are accepted, so that a client can not
+/// write outside of it: absolute paths and `.`, `..` components are rejected.
+fn resolve_backup(root: &Path, path: &str) -> Result<PathBuf, error::Error> {
+    let relative = Path::new(path);
+    let named = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if path.is_empty() || !named {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a relative path under the backup root", path),
+        )
+        .into());
+    }
+    Ok(root.join(relative))
 }
 
 /// Make actor from `ToyServer`
@@ -138,14 +228,29 @@ impl Handler<Get> for ToyServer {
     fn handle(&mut self, msg: Get, _: &mut Context<Self>) -> Self::Result {
         let Get { id, key } = msg;
         println!("client({}) get {}", id, key);
-        let value = self.store.get(key.parse().unwrap())?;
+        let value = self.store.get(key.as_bytes())?;
         match value {
             None => Ok("".to_owned()),
-            Some(v) => Ok(v.to_string()),
+            Some(v) => Ok(String::from_utf8_lossy(&v).into_owned()),
         }
     }
 }
 
+/// Get values of keys
+impl Handler<MGet> for ToyServer {
+    type Result = Result<Vec<Option<String>>, error::Error>;
+
+    fn handle(&mut self, msg: MGet, _: &mut Context<Self>) -> Self::Result {
+        let MGet { id, keys } = msg;
+        println!("client({}) mget {:?}", id, keys);
+        let values = self.store.multi_get(&keys)?;
+        Ok(values
+            .into_iter()
+            .map(|v| v.map(|v| String::from_utf8_lossy(&v).into_owned()))
+            .collect())
+    }
+}
+
 /// Put kv pair
 impl Handler<Put> for ToyServer {
     type Result = Result<(), error::Error>;
@@ -153,10 +258,7 @@ impl Handler<Put> for ToyServer {
     fn handle(&mut self, msg: Put, _: &mut Context<Self>) -> Self::Result {
         let Put { id, key, value } = msg;
         println!("client({}) put ({}, {})", id, key, value);
-        self.store.put(
-            key.parse().unwrap(),
-            kv::Value::Valid(Box::new(value.parse().unwrap())),
-        )?;
+        self.store.put(key.as_bytes(), value.as_bytes())?;
         Ok(())
     }
 }
@@ -168,23 +270,123 @@ impl Handler<Delete> for ToyServer {
     fn handle(&mut self, msg: Delete, _: &mut Context<Self>) -> Self::Result {
         let Delete { id, key } = msg;
         println!("client({}) delete {}", id, key);
-        self.store.delete(key.parse().unwrap())?;
+        self.store.delete(key.as_bytes())?;
         Ok(())
     }
 }
 
-/// Delete value of key
+/// Compare and swap the value of key
+impl Handler<Cas> for ToyServer {
+    type Result = Result<bool, error::Error>;
+
+    fn handle(&mut self, msg: Cas, _: &mut Context<Self>) -> Self::Result {
+        let Cas {
+            id,
+            key,
+            expected,
+            new,
+        } = msg;
+        println!("client({}) cas {} ({:?} -> {:?})", id, key, expected, new);
+        self.store.compare_and_swap(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )
+    }
+}
+
+/// Checkpoint the store into a directory
+/// Copied on a thread of its own, the server goes on handling messages meanwhile.
+impl Handler<Checkpoint> for ToyServer {
+    type Result = ResponseFuture<u64, error::Error>;
+
+    fn handle(&mut self, msg: Checkpoint, _: &mut Context<Self>) -> Self::Result {
+        let Checkpoint { id, dir } = msg;
+        println!("client({}) checkpoint {}", id, dir);
+        let dir = match resolve_backup(&self.backup_root, &dir) {
+            Ok(dir) => dir,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let store = Arc::clone(&self.store);
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(store.checkpoint(&dir));
+        });
+        Box::new(rx.then(|checkpoint| match checkpoint {
+            Ok(checkpoint) => checkpoint,
+            Err(_) => Err(io::Error::other("checkpoint thread ended").into()),
+        }))
+    }
+}
+
+/// Export the writes since a ventry into a delta file
+impl Handler<Export> for ToyServer {
+    type Result = Result<u64, error::Error>;
+
+    fn handle(&mut self, msg: Export, _: &mut Context<Self>) -> Self::Result {
+        let Export { id, since, path } = msg;
+        println!("client({}) export {} since {}", id, path, since);
+        let path = resolve_backup(&self.backup_root, &path)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        self.store.export_delta(since, &path)
+    }
+}
+
+/// Send scanned kv pairs to session
+fn send_scan(addr: &Addr<session::ToySession>, iter: StoreIter) {
+    for kv in iter {
+        match kv {
+            Ok((k, v)) => addr.do_send(session::Next {
+                key: String::from_utf8_lossy(&k).into_owned(),
+                value: String::from_utf8_lossy(&v).into_owned(),
+            }),
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Scan kv pairs
 impl Handler<Scan> for ToyServer {
     type Result = ();
 
     fn handle(&mut self, msg: Scan, _: &mut Context<Self>) {
         let id = msg.0;
         let addr = &self.sessions[&id];
-        for (k, v) in self.store.scan() {
-            addr.do_send(session::Next {
-                key: k.to_string(),
-                value: v.to_string(),
-            });
+        send_scan(addr, self.store.scan());
+    }
+}
+
+/// Scan kv pairs with prefix
+impl Handler<ScanPrefix> for ToyServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanPrefix, _: &mut Context<Self>) {
+        let ScanPrefix { id, prefix } = msg;
+        println!("client({}) scan prefix {}", id, prefix);
+        let addr = &self.sessions[&id];
+        send_scan(addr, self.store.scan_prefix(prefix.as_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_backup_test() {
+        let root = Path::new("toydb/backups");
+        assert_eq!(
+            resolve_backup(root, "daily/1.delta").unwrap(),
+            root.join("daily/1.delta")
+        );
+        for path in &["", "/etc", "../toy.k", "daily/../../toy.k", "./daily"] {
+            let err = io::Error::from(resolve_backup(root, path).err().unwrap());
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
         }
     }
 }
@@ -88,6 +88,27 @@ impl StreamHandler<ToyRequest, io::Error> for ToySession {
                     })
                     .wait(ctx)
             }
+            ToyRequest::MGet(keys) => {
+                self.addr
+                    .send(server::MGet {
+                        id: self.id,
+                        keys: keys.clone(),
+                    })
+                    .into_actor(self) // <- create actor compatible future
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(mget_res) => match mget_res {
+                                Ok(values) => act.framed.write(ToyResponse::Values(
+                                    keys.into_iter().zip(values).collect(),
+                                )),
+                                Err(e) => eprintln!("{}", e),
+                            },
+                            _ => eprintln!("Can not connect to toy server"),
+                        }
+                        actix::fut::ok(())
+                    })
+                    .wait(ctx)
+            }
             ToyRequest::Put((k, v)) => {
                 self.addr
                     .send(server::Put {
@@ -130,12 +151,84 @@ impl StreamHandler<ToyRequest, io::Error> for ToySession {
                     })
                     .wait(ctx)
             }
+            ToyRequest::Cas((k, expected, new)) => {
+                self.addr
+                    .send(server::Cas {
+                        id: self.id,
+                        key: k.clone(),
+                        expected,
+                        new,
+                    })
+                    .into_actor(self) // <- create actor compatible future
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(cas_res) => match cas_res {
+                                Ok(swapped) => {
+                                    act.framed.write(ToyResponse::Swapped((k.clone(), swapped)))
+                                }
+                                Err(e) => eprintln!("{}", e),
+                            },
+                            _ => eprintln!("Can not connect to toy server"),
+                        }
+                        actix::fut::ok(())
+                    })
+                    .wait(ctx)
+            }
+            ToyRequest::Checkpoint(dir) => {
+                self.addr
+                    .send(server::Checkpoint {
+                        id: self.id,
+                        dir: dir.clone(),
+                    })
+                    .into_actor(self) // <- create actor compatible future
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(checkpoint_res) => match checkpoint_res {
+                                Ok(ventry) => act
+                                    .framed
+                                    .write(ToyResponse::Checkpointed((dir.clone(), ventry))),
+                                Err(e) => eprintln!("{}", e),
+                            },
+                            _ => eprintln!("Can not connect to toy server"),
+                        }
+                        actix::fut::ok(())
+                    })
+                    .wait(ctx)
+            }
+            ToyRequest::Export((since, path)) => {
+                self.addr
+                    .send(server::Export {
+                        id: self.id,
+                        since,
+                        path: path.clone(),
+                    })
+                    .into_actor(self) // <- create actor compatible future
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(export_res) => match export_res {
+                                Ok(ventry) => act
+                                    .framed
+                                    .write(ToyResponse::Exported((path.clone(), ventry))),
+                                Err(e) => eprintln!("{}", e),
+                            },
+                            _ => eprintln!("Can not connect to toy server"),
+                        }
+                        actix::fut::ok(())
+                    })
+                    .wait(ctx)
+            }
 
             // we update heartbeat time on ping from peer
             ToyRequest::Ping => self.hb = Instant::now(),
             ToyRequest::Scan => {
                 self.addr.do_send(server::Scan(self.id));
             }
+            ToyRequest::ScanPrefix(prefix) => {
+                self.addr.do_send(server::ScanPrefix {
+                    id: self.id,
+                    prefix,
+                });
+            }
         }
     }
 }
@@ -1,8 +1,14 @@
 #[cfg(test)]
 mod store_integration_test {
-    use toy_kv::engine::{kv, store};
+    use toy_kv::engine::{
+        batch, cache, delta, error, fsck, kv, options, shard, store, superblock, util,
+    };
 
+    use std::fs;
     use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
     use tempfile::tempdir;
 
     fn tmpfile(name: &str) -> (PathBuf, PathBuf, PathBuf) {
@@ -21,7 +27,7 @@ mod store_integration_test {
     fn store_put_get() {
         let (k, v, b) = tmpfile("test_store_put_get");
         {
-            let mut db = store::Store::new(&k, &v, &b).unwrap();
+            let db = store::Store::new(&k, &v, &b).unwrap();
             let kvs = vec![
                 ("key00", "value00"),
                 ("key02", "value02"),
@@ -32,24 +38,17 @@ mod store_integration_test {
             ];
             for _ in 0..3 {
                 for kv in &kvs {
-                    db.put(
-                        kv.0.parse().unwrap(),
-                        kv::Value::Valid(Box::new(kv.1.parse().unwrap())),
-                    )
-                    .unwrap();
+                    db.put(kv.0.as_bytes(), kv.1.as_bytes()).unwrap();
                 }
             }
         }
         {
             // Restore from file
-            let mut db = store::Store::new(&k, &v, &b).unwrap();
+            let db = store::Store::new(&k, &v, &b).unwrap();
 
             for i in 0..=5 {
-                let v = db
-                    .get(format!("key0{}", i).parse().unwrap())
-                    .unwrap()
-                    .unwrap();
-                assert_eq!(v.to_string(), format!("value0{}", i))
+                let v = db.get(format!("key0{}", i).as_bytes()).unwrap().unwrap();
+                assert_eq!(v, format!("value0{}", i).as_bytes())
             }
         }
     }
@@ -57,7 +56,7 @@ mod store_integration_test {
     #[test]
     fn store_delete() {
         let (k, v, b) = tmpfile("test_store_delete");
-        let mut db = store::Store::new(&k, &v, &b).unwrap();
+        let db = store::Store::new(&k, &v, &b).unwrap();
         let kvs = vec![
             ("key00", "value00"),
             ("key02", "value02"),
@@ -67,28 +66,21 @@ mod store_integration_test {
             ("key04", "value04"),
         ];
         for kv in &kvs {
-            db.put(
-                kv.0.parse().unwrap(),
-                kv::Value::Valid(Box::new(kv.1.parse().unwrap())),
-            )
-            .unwrap();
+            db.put(kv.0.as_bytes(), kv.1.as_bytes()).unwrap();
         }
-        db.delete(kvs[4].0.parse().unwrap()).unwrap();
+        db.delete(kvs[4].0.as_bytes()).unwrap();
         for i in 0..=4 {
-            let v = db
-                .get(format!("key0{}", i).parse().unwrap())
-                .unwrap()
-                .unwrap();
-            assert_eq!(v.to_string(), format!("value0{}", i))
+            let v = db.get(format!("key0{}", i).as_bytes()).unwrap().unwrap();
+            assert_eq!(v, format!("value0{}", i).as_bytes())
         }
-        let invalid = db.get(kvs[4].0.parse().unwrap()).unwrap();
+        let invalid = db.get(kvs[4].0.as_bytes()).unwrap();
         assert!(invalid.is_none());
     }
 
     #[test]
     fn store_scan() {
         let (k, v, b) = tmpfile("test_store_scan");
-        let mut db = store::Store::new(&k, &v, &b).unwrap();
+        let db = store::Store::new(&k, &v, &b).unwrap();
         let kvs = vec![
             ("key00", "value00"),
             ("key02", "value02"),
@@ -98,17 +90,13 @@ mod store_integration_test {
             ("key04", "value04"),
         ];
         for kv in kvs {
-            db.put(
-                kv.0.parse().unwrap(),
-                kv::Value::Valid(Box::new(kv.1.parse().unwrap())),
-            )
-            .unwrap();
+            db.put(kv.0.as_bytes(), kv.1.as_bytes()).unwrap();
         }
         let mut iter = db.scan();
         for i in 0..=5 {
-            let (k, v) = iter.next().unwrap();
-            assert_eq!(k.to_string(), format!("key0{}", i));
-            assert_eq!(v.to_string(), format!("value0{}", i));
+            let (k, v) = iter.next().unwrap().unwrap();
+            assert_eq!(k, format!("key0{}", i).as_bytes());
+            assert_eq!(v, format!("value0{}", i).as_bytes());
         }
         let res = iter.next();
         assert!(res.is_none());
@@ -118,25 +106,1276 @@ mod store_integration_test {
     fn store_to_grow() {
         let (k, v, b) = tmpfile("test_store_to_grow");
         {
-            let mut db = store::Store::new(&k, &v, &b).unwrap();
+            let db = store::Store::new(&k, &v, &b).unwrap();
             for i in 0..100_000 {
-                db.put(
-                    format!("k{}", i).parse().unwrap(),
-                    kv::Value::Valid(Box::new(format!("v{}", i).parse().unwrap())),
+                db.put(format!("k{}", i).as_bytes(), format!("v{}", i).as_bytes())
+                    .unwrap();
+            }
+        }
+
+        {
+            // Restore from file
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            for i in (0..100_000).step_by(997) {
+                let v = db.get(format!("k{}", i).as_bytes()).unwrap().unwrap();
+                assert_eq!(v, format!("v{}", i).as_bytes())
+            }
+        }
+    }
+
+    #[test]
+    fn store_variable_length() {
+        let (k, v, b) = tmpfile("test_store_variable_length");
+        let value = |i: usize| vec![(i % 251) as u8; (i * 37) % 12_000 + 1];
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            // Enough to flush the buffer a few times
+            for i in 0..4_000 {
+                let key = format!("{}{}", "k".repeat(i % 100 + 1), i);
+                db.put(key.as_bytes(), &value(i)).unwrap();
+            }
+            let err = db.put(&[1; kv::MAX_KEY_SIZE + 1], b"v").err().unwrap();
+            assert_eq!(err, error::Error::ContentExceed);
+            let err = db.put(b"", b"v").err().unwrap();
+            assert_eq!(err, error::Error::InvalidKeySize);
+        }
+        {
+            // Restore from file
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            for i in 0..4_000 {
+                let key = format!("{}{}", "k".repeat(i % 100 + 1), i);
+                let v = db.get(key.as_bytes()).unwrap().unwrap();
+                assert_eq!(v, value(i));
+            }
+            db.put(b"after", b"restore").unwrap();
+            assert_eq!(db.get(b"after").unwrap().unwrap(), b"restore");
+            assert_eq!(db.scan().count(), 4_001);
+        }
+    }
+
+    #[test]
+    fn store_compact() {
+        let (k, v, b) = tmpfile("test_store_compact");
+        {
+            let mut db = store::Store::new(&k, &v, &b).unwrap();
+            for round in 0..10 {
+                for i in 0..1_000 {
+                    let value = format!("{}-{}", "v".repeat(4_000), round * i);
+                    db.put(format!("k{}", i).as_bytes(), value.as_bytes())
+                        .unwrap();
+                }
+            }
+            for i in (0..1_000).step_by(2) {
+                db.delete(format!("k{}", i).as_bytes()).unwrap();
+            }
+            let before = fs::metadata(&v).unwrap().len();
+            db.compact().unwrap();
+            assert!(fs::metadata(&v).unwrap().len() < before);
+            for i in 0..1_000 {
+                let value = db.get(format!("k{}", i).as_bytes()).unwrap();
+                if i % 2 == 0 {
+                    assert!(value.is_none());
+                } else {
+                    let expected = format!("{}-{}", "v".repeat(4_000), 9 * i);
+                    assert_eq!(value.unwrap(), expected.as_bytes());
+                }
+            }
+            db.put(b"k0", b"after compaction").unwrap();
+        }
+        {
+            // Restore from file
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            assert_eq!(db.get(b"k0").unwrap().unwrap(), b"after compaction");
+            assert_eq!(db.get(b"k2").unwrap(), None);
+            assert_eq!(db.scan().count(), 501);
+        }
+    }
+
+    #[test]
+    fn store_compact_interrupted() {
+        let (k, v, b) = tmpfile("test_store_compact_interrupted");
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key", b"value").unwrap();
+        }
+        // Crashed before commit, the half written files are dropped
+        let key_tmp = format!("{}.compact", k.display());
+        let value_tmp = format!("{}.compact", v.display());
+        fs::write(&key_tmp, b"garbage").unwrap();
+        fs::write(&value_tmp, b"garbage").unwrap();
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        assert_eq!(db.get(b"key").unwrap().unwrap(), b"value");
+        assert!(fs::metadata(&key_tmp).is_err());
+        assert!(fs::metadata(&value_tmp).is_err());
+    }
+
+    #[test]
+    fn store_corrupted() {
+        let (k, v, b) = tmpfile("test_store_corrupted");
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key00", b"value00").unwrap();
+            db.put(b"key01", b"value01").unwrap();
+        }
+        // Flip a byte of the second value in buffer
+        let mut buffer = fs::read(&b).unwrap();
+        let offset = superblock::SUPERBLOCK_SIZE + kv::VALUE_HEADER_SIZE as u64 + 7;
+        buffer[offset as usize + kv::VALUE_HEADER_SIZE] ^= 0xff;
+        fs::write(&b, &buffer).unwrap();
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            assert_eq!(db.get(b"key00").unwrap().unwrap(), b"value00");
+            let corrupted = error::Error::Corrupted {
+                file: b.clone(),
+                offset,
+            };
+            assert_eq!(db.get(b"key01").err().unwrap(), corrupted);
+            let mut iter = db.scan();
+            assert!(iter.next().unwrap().is_ok());
+            assert_eq!(iter.next().unwrap().err().unwrap(), corrupted);
+        }
+        // Flip a byte of the first key
+        let offset = superblock::SUPERBLOCK_SIZE;
+        let mut keys = fs::read(&k).unwrap();
+        keys[offset as usize + kv::KEY_HEADER_SIZE] ^= 0xff;
+        fs::write(&k, &keys).unwrap();
+        let err = store::Store::new(&k, &v, &b).err().unwrap();
+        assert_eq!(
+            err,
+            error::Error::Corrupted {
+                file: k.clone(),
+                offset
+            }
+        );
+
+        // Tear the last key, as a write cut short
+        keys[offset as usize + kv::KEY_HEADER_SIZE] ^= 0xff;
+        let offset = offset + kv::key_record_size(5) as u64;
+        let end = offset as usize + kv::key_record_size(5);
+        for byte in keys[end - 4..end].iter_mut() {
+            *byte = 0;
+        }
+        fs::write(&k, &keys).unwrap();
+        let err = store::Store::new(&k, &v, &b).err().unwrap();
+        assert_eq!(err, error::Error::Corrupted { file: k, offset });
+    }
+
+    #[test]
+    fn store_range() {
+        use std::ops::Bound;
+
+        let (k, v, b) = tmpfile("test_store_range");
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        for round in 0..3 {
+            for i in 0..10 {
+                let value = format!("value{}{}", i, round);
+                db.put(format!("key{}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+        }
+        db.delete(b"key4").unwrap();
+
+        let keys = |iter: store::StoreIter| -> Vec<String> {
+            iter.map(|kv| String::from_utf8(kv.unwrap().0).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            keys(db.range(&b"key2"[..]..&b"key6"[..])),
+            ["key2", "key3", "key5"]
+        );
+        assert_eq!(
+            keys(db.range(&b"key2"[..]..=&b"key6"[..])),
+            ["key2", "key3", "key5", "key6"]
+        );
+        assert_eq!(
+            keys(
+                db.range::<&[u8], _>((
+                    Bound::Excluded(&b"key2"[..]),
+                    Bound::Included(&b"key5"[..])
+                ))
+            ),
+            ["key3", "key5"]
+        );
+        assert_eq!(keys(db.range_from(b"key8")), ["key8", "key9"]);
+        assert_eq!(keys(db.range_from(b"key85")), ["key9"]);
+        assert_eq!(keys(db.range(..&b"key1"[..])), ["key0"]);
+        assert!(keys(db.range_from(b"key99")).is_empty());
+        assert!(keys(db.range(&b"a"[..]..&b"b"[..])).is_empty());
+
+        // Only the latest version
+        let (_, value) = db.range_from(b"key7").next().unwrap().unwrap();
+        assert_eq!(value, b"value72");
+    }
+
+    #[test]
+    fn store_scan_prefix() {
+        let (k, v, b) = tmpfile("test_store_scan_prefix");
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        let kvs = vec![
+            ("ord:0002", "o2"),
+            ("usr:0001", "u1"),
+            ("ord:0001", "o1"),
+            ("usr", "u"),
+            ("usr:0002", "u2"),
+            ("usr;0001", "x"),
+        ];
+        for kv in &kvs {
+            db.put(kv.0.as_bytes(), kv.1.as_bytes()).unwrap();
+        }
+        db.delete(b"usr:0002").unwrap();
+        db.put(b"usr:0001", b"u1-new").unwrap();
+
+        let kvs: Vec<(Vec<u8>, Vec<u8>)> = db.scan_prefix(b"usr:").map(|kv| kv.unwrap()).collect();
+        assert_eq!(kvs, vec![(b"usr:0001".to_vec(), b"u1-new".to_vec())]);
+        assert_eq!(db.scan_prefix(b"ord:").count(), 2);
+        assert_eq!(db.scan_prefix(b"").count(), 5);
+        assert_eq!(db.scan_prefix(b"zzz").count(), 0);
+    }
+
+    #[test]
+    fn store_scan_rev() {
+        let (k, v, b) = tmpfile("test_store_scan_rev");
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        for round in 0..3 {
+            for i in 0..10 {
+                let value = format!("value{}{}", i, round);
+                db.put(format!("key{}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+        }
+        db.delete(b"key9").unwrap();
+        db.delete(b"key4").unwrap();
+
+        let kvs: Vec<(String, String)> = db
+            .scan_rev()
+            .map(|kv| {
+                let (k, v) = kv.unwrap();
+                (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())
+            })
+            .collect();
+        let expected: Vec<(String, String)> = [8, 7, 6, 5, 3, 2, 1, 0]
+            .iter()
+            .map(|i| (format!("key{}", i), format!("value{}2", i)))
+            .collect();
+        assert_eq!(kvs, expected);
+
+        let keys: Vec<Vec<u8>> = db
+            .range_rev(&b"key2"[..]..&b"key6"[..])
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        assert_eq!(keys, [b"key5", b"key3", b"key2"]);
+
+        // Last N keys
+        let keys: Vec<Vec<u8>> = db.scan_rev().take(2).map(|kv| kv.unwrap().0).collect();
+        assert_eq!(keys, [b"key8", b"key7"]);
+
+        // From both ends
+        let mut iter = db.range(&b"key1"[..]..=&b"key3"[..]);
+        assert_eq!(iter.next_back().unwrap().unwrap().0, b"key3");
+        assert_eq!(iter.next().unwrap().unwrap().0, b"key1");
+        assert_eq!(iter.next_back().unwrap().unwrap().0, b"key2");
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+    }
+
+    #[test]
+    fn store_snapshot() {
+        let (k, v, b) = tmpfile("test_store_snapshot");
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        db.put(b"key0", b"value0").unwrap();
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+        let snapshot = db.snapshot();
+
+        // Writes after the snapshot
+        db.put(b"key1", b"value1-new").unwrap();
+        db.delete(b"key2").unwrap();
+        db.put(b"key3", b"value3").unwrap();
+
+        assert_eq!(db.get(b"key1").unwrap().unwrap(), b"value1-new");
+        assert_eq!(db.get(b"key2").unwrap(), None);
+        assert_eq!(snapshot.get(b"key1").unwrap().unwrap(), b"value1");
+        assert_eq!(snapshot.get(b"key2").unwrap().unwrap(), b"value2");
+        assert_eq!(snapshot.get(b"key3").unwrap(), None);
+
+        let kvs: Vec<(Vec<u8>, Vec<u8>)> = snapshot.scan().map(|kv| kv.unwrap()).collect();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..3)
+            .map(|i| {
+                (
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
                 )
+            })
+            .collect();
+        assert_eq!(kvs, expected);
+        let rev: Vec<(Vec<u8>, Vec<u8>)> = snapshot.scan().rev().map(|kv| kv.unwrap()).collect();
+        assert_eq!(rev, expected.into_iter().rev().collect::<Vec<_>>());
+        assert_eq!(snapshot.range(&b"key1"[..]..).count(), 2);
+        assert_eq!(db.scan().count(), 3);
+    }
+
+    #[test]
+    fn store_write_batch() {
+        let (k, v, b) = tmpfile("test_store_write_batch");
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key0", b"value0").unwrap();
+            let mut batch = batch::WriteBatch::new();
+            batch
+                .put(b"key1", b"value1")
+                .delete(b"key0")
+                .put(b"key2", b"value2");
+            db.write(batch).unwrap();
+
+            // An invalid operation rejects the whole batch
+            let mut batch = batch::WriteBatch::new();
+            batch.put(b"key3", b"value3").put(b"", b"empty");
+            assert_eq!(db.write(batch).err().unwrap(), error::Error::InvalidKeySize);
+            assert_eq!(db.get(b"key3").unwrap(), None);
+        }
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            assert_eq!(db.get(b"key0").unwrap(), None);
+            assert_eq!(db.get(b"key1").unwrap().unwrap(), b"value1");
+            assert_eq!(db.get(b"key2").unwrap().unwrap(), b"value2");
+            assert_eq!(db.get(b"key3").unwrap(), None);
+            db.put(b"key3", b"value3").unwrap();
+            assert_eq!(db.scan().count(), 3);
+        }
+    }
+
+    #[test]
+    fn store_compare_and_swap() {
+        let (k, v, b) = tmpfile("test_store_compare_and_swap");
+        let db = store::Store::new(&k, &v, &b).unwrap();
+
+        assert!(db.put_if_absent(b"key", b"value0").unwrap());
+        assert!(!db.put_if_absent(b"key", b"value1").unwrap());
+        assert_eq!(db.get(b"key").unwrap().unwrap(), b"value0");
+
+        assert!(!db
+            .compare_and_swap(b"key", Some(b"value1"), Some(b"value2"))
+            .unwrap());
+        assert!(db
+            .compare_and_swap(b"key", Some(b"value0"), Some(b"value2"))
+            .unwrap());
+        assert_eq!(db.get(b"key").unwrap().unwrap(), b"value2");
+
+        assert!(!db.delete_if_equals(b"key", b"value0").unwrap());
+        assert!(db.delete_if_equals(b"key", b"value2").unwrap());
+        assert_eq!(db.get(b"key").unwrap(), None);
+
+        // Both absent
+        assert!(db.compare_and_swap(b"key", None, None).unwrap());
+        assert!(!db.delete_if_equals(b"key", b"value2").unwrap());
+    }
+
+    #[test]
+    fn store_put_with_ttl() {
+        let (k, v, b) = tmpfile("test_store_put_with_ttl");
+        let mut db = store::Store::new(&k, &v, &b).unwrap();
+        db.put(b"key0", b"value0").unwrap();
+        db.put_with_ttl(b"key1", b"value1", Duration::from_millis(50))
+            .unwrap();
+        db.put_with_ttl(b"key2", b"value2", Duration::from_secs(3600))
+            .unwrap();
+        // Too long to represent, never expires
+        db.put_with_ttl(b"key4", b"value4", Duration::from_secs(u64::MAX / 100))
+            .unwrap();
+        db.put_with_ttl(b"key5", b"value5", Duration::new(u64::MAX, 999_999_999))
+            .unwrap();
+        assert_eq!(db.get(b"key1").unwrap().unwrap(), b"value1");
+        assert_eq!(db.get(b"key5").unwrap().unwrap(), b"value5");
+        db.delete(b"key4").unwrap();
+        db.delete(b"key5").unwrap();
+        assert_eq!(db.scan().count(), 3);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(db.get(b"key1").unwrap(), None);
+        assert_eq!(db.get(b"key2").unwrap().unwrap(), b"value2");
+        assert_eq!(db.scan().count(), 2);
+        assert_eq!(db.scan_rev().count(), 2);
+        assert!(db.put_if_absent(b"key1", b"value1-new").unwrap());
+
+        // Compaction drops expired keys, and keeps the expiry of the others
+        db.put_with_ttl(b"key3", b"value3", Duration::from_millis(50))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        db.compact().unwrap();
+        assert_eq!(db.get(b"key3").unwrap(), None);
+        drop(db);
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        let keys: Vec<Vec<u8>> = db.scan().map(|kv| kv.unwrap().0).collect();
+        assert_eq!(keys, [&b"key0"[..], b"key1", b"key2"]);
+    }
+
+    #[test]
+    fn store_concurrent_readers() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<store::Store>();
+
+        let (k, v, b) = tmpfile("test_store_concurrent_readers");
+        // Flushed every few puts, while being read
+        let options = options::StoreOptions {
+            buffer_size: 16384,
+            ..options::StoreOptions::default()
+        };
+        let db = Arc::new(store::Store::with_options(&k, &v, &b, options).unwrap());
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(key.as_bytes(), "v".repeat(4_000).as_bytes())
                 .unwrap();
+        }
+
+        // Readers run while the writer appends, no lock around the store
+        let writer = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for i in 100..200 {
+                    let key = format!("key{:03}", i);
+                    db.put(key.as_bytes(), "v".repeat(4_000).as_bytes())
+                        .unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("key{:03}", i);
+                        assert_eq!(db.get(key.as_bytes()).unwrap().unwrap().len(), 4_000);
+                        assert!(db.scan().count() >= 100);
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(db.scan().count(), 200);
+
+        // Writers are serialized, a single one puts the key
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || db.put_if_absent(b"owner", &[i]).unwrap())
+            })
+            .collect();
+        let won = writers
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .filter(|&won| won)
+            .count();
+        assert_eq!(won, 1);
+    }
+
+    #[test]
+    fn store_sharded() {
+        let tmp = tempdir().unwrap().into_path();
+        {
+            let db = shard::ShardedStore::new(&tmp, 4).unwrap();
+            for i in (0..100).rev() {
+                let key = format!("key{:03}", i);
+                let value = format!("value{:03}", i);
+                db.put(key.as_bytes(), value.as_bytes()).unwrap();
             }
+            db.delete(b"key050").unwrap();
+            assert_eq!(db.get(b"key050").unwrap(), None);
         }
+        // Every shard owns its own files
+        for i in 0..4 {
+            assert!(tmp.join(format!("toy-{}.k", i)).exists());
+        }
+
+        let db = shard::ShardedStore::new(&tmp, 4).unwrap();
+        assert_eq!(db.get(b"key042").unwrap().unwrap(), b"value042");
+        let keys: Vec<Vec<u8>> = db.scan().map(|kv| kv.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> = (0..100)
+            .filter(|i| *i != 50)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        let keys: Vec<Vec<u8>> = db
+            .range(&b"key010"[..]..&b"key020"[..])
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        assert_eq!(keys, expected[10..20].to_vec());
+        drop(db);
+
+        // The number of shards is kept
+        assert_eq!(fs::read_to_string(tmp.join("SHARDS")).unwrap(), "4\n");
+        assert_eq!(
+            shard::ShardedStore::new(&tmp, 3).err().unwrap(),
+            error::Error::InvalidOptions {
+                reason: format!("{:?} was created with 4 shards, opened with 3", tmp),
+            }
+        );
+        assert!(shard::ShardedStore::new(&tmp, 0).is_err());
+        // Without it, shards are not opened with a guessed number
+        fs::remove_file(tmp.join("SHARDS")).unwrap();
+        assert_eq!(
+            shard::ShardedStore::new(&tmp, 4).err().unwrap(),
+            error::Error::InvalidOptions {
+                reason: format!("{:?} holds shards but no SHARDS", tmp),
+            }
+        );
+        assert!(!tmp.join("SHARDS").exists());
+    }
 
+    #[test]
+    fn store_index_checkpoint() {
+        let (k, v, b) = tmpfile("test_store_index_checkpoint");
+        let index = {
+            let mut name = k.clone().into_os_string();
+            name.push(".idx");
+            PathBuf::from(name)
+        };
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key00", b"value00").unwrap();
+            db.put(b"key01", b"value01").unwrap();
+            db.save_index().unwrap();
+            // Replayed after the checkpoint
+            db.put(b"key00", b"value00-new").unwrap();
+            let mut batch = batch::WriteBatch::new();
+            batch.put(b"key02", b"value02").delete(b"key01");
+            db.write(batch).unwrap();
+        }
+        assert!(index.exists());
+        // Records before the checkpoint are not read again
+        let offset = superblock::SUPERBLOCK_SIZE;
+        let mut keys = fs::read(&k).unwrap();
+        keys[offset as usize + kv::KEY_HEADER_SIZE] ^= 0xff;
+        fs::write(&k, &keys).unwrap();
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            assert_eq!(db.get(b"key00").unwrap().unwrap(), b"value00-new");
+            assert_eq!(db.get(b"key01").unwrap(), None);
+            assert_eq!(db.get(b"key02").unwrap().unwrap(), b"value02");
+            db.put(b"key03", b"value03").unwrap();
+            assert_eq!(db.scan().count(), 3);
+        }
+        // A broken checkpoint falls back to the keys file
+        fs::write(&index, b"broken").unwrap();
+        let err = store::Store::new(&k, &v, &b).err().unwrap();
+        assert_eq!(
+            err,
+            error::Error::Corrupted {
+                file: k.clone(),
+                offset
+            }
+        );
+        let mut keys = fs::read(&k).unwrap();
+        keys[offset as usize + kv::KEY_HEADER_SIZE] ^= 0xff;
+        fs::write(&k, &keys).unwrap();
         {
-            // Restore from file
             let mut db = store::Store::new(&k, &v, &b).unwrap();
-            // let v = db.get("k65536".parse().unwrap()).unwrap().unwrap();
-            for i in 99_999..100_000 {
-                let v = db.get(format!("k{}", i).parse().unwrap()).unwrap().unwrap();
-                assert_eq!(v.to_string(), format!("v{}", i))
+            assert_eq!(db.scan().count(), 3);
+            // Compaction drops the checkpoint of the old keys file
+            db.save_index().unwrap();
+            db.compact().unwrap();
+            assert!(!index.exists());
+            assert_eq!(db.scan().count(), 3);
+        }
+    }
+
+    #[test]
+    fn store_index_checkpoint_versions() {
+        let (k, v, b) = tmpfile("test_store_index_checkpoint_versions");
+        let index = util::with_suffix(&k, util::INDEX_SUFFIX);
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        db.put(b"key00", b"value00-1").unwrap();
+        db.put(b"key01", b"value01").unwrap();
+        let snapshot = db.snapshot();
+        db.put(b"key00", b"value00-2").unwrap();
+        db.put(b"key00", b"value00-3").unwrap();
+        {
+            let _gone = db.snapshot();
+            db.put(b"key01", b"value01-new").unwrap();
+        }
+        db.save_index().unwrap();
+
+        // The latest versions, and the one the live snapshot sees
+        let checkpoint = util::load_index(&index).unwrap().unwrap();
+        assert_eq!(checkpoint.ventry, 5);
+        let versions: Vec<(Vec<u8>, u64)> = checkpoint
+            .keys
+            .iter()
+            .map(|key| (key.inner.raw.clone(), key.ventry))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                (b"key00".to_vec(), 0),
+                (b"key00".to_vec(), 3),
+                (b"key01".to_vec(), 1),
+                (b"key01".to_vec(), 4),
+            ]
+        );
+        assert_eq!(snapshot.get(b"key00").unwrap().unwrap(), b"value00-1");
+        drop(snapshot);
+        drop(db);
+
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        assert_eq!(db.get(b"key00").unwrap().unwrap(), b"value00-3");
+        assert_eq!(db.get(b"key01").unwrap().unwrap(), b"value01-new");
+        drop(db);
+
+        // A truncated checkpoint is ignored
+        let bytes = fs::read(&index).unwrap();
+        fs::write(&index, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(util::load_index(&index).unwrap().is_none());
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        assert_eq!(db.get(b"key00").unwrap().unwrap(), b"value00-3");
+    }
+
+    #[test]
+    fn store_block_cache() {
+        let (k, v, b) = tmpfile("test_store_block_cache");
+        let options = options::StoreOptions {
+            cache_size: 4 * 4096,
+            ..Default::default()
+        };
+        let mut db = store::Store::with_options(&k, &v, &b, options).unwrap();
+        for i in 0..8 {
+            let key = format!("key{}", i);
+            db.put(key.as_bytes(), "v".repeat(4_000).as_bytes())
+                .unwrap();
+        }
+        // Values in the buffer are not cached
+        db.get(b"key0").unwrap().unwrap();
+        assert_eq!(db.cache_stats(), cache::CacheStats::default());
+
+        // Move the values to the values file
+        db.compact().unwrap();
+        db.get(b"key0").unwrap().unwrap();
+        let misses = db.cache_stats().misses;
+        assert!(misses > 0);
+        db.get(b"key0").unwrap().unwrap();
+        assert_eq!(db.cache_stats().misses, misses);
+        assert!(db.cache_stats().hits > 0);
+
+        // Reading all of them evicts the first ones
+        assert_eq!(db.scan().count(), 8);
+        let stats = db.cache_stats();
+        db.get(b"key0").unwrap().unwrap();
+        assert!(db.cache_stats().misses > stats.misses);
+    }
+
+    #[test]
+    fn store_multi_get() {
+        let (k, v, b) = tmpfile("test_store_multi_get");
+        let mut db = store::Store::new(&k, &v, &b).unwrap();
+        for i in 0..64 {
+            let key = format!("key{:02}", i);
+            db.put(
+                key.as_bytes(),
+                format!("value{}", i).repeat(i * 20).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.delete(b"key03").unwrap();
+        // Move the values to the values file, then add some to the buffer
+        db.compact().unwrap();
+        db.put(b"key05", b"buffered").unwrap();
+        db.put_with_ttl(b"key07", b"expired", Duration::from_millis(1))
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        let keys: Vec<String> = [63, 1, 3, 5, 7, 40, 1, 99, 10]
+            .iter()
+            .map(|i| format!("key{:02}", i))
+            .collect();
+        let values = db.multi_get(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values.iter()) {
+            assert_eq!(value, &db.get(key.as_bytes()).unwrap(), "{}", key);
+        }
+        assert_eq!(values[3], Some(b"buffered".to_vec()));
+        assert_eq!(values[2], None);
+        assert_eq!(values[4], None);
+        assert_eq!(values[7], None);
+        assert_eq!(values[1], values[6]);
+        assert!(db.multi_get::<&[u8]>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn store_migrate_baseline() {
+        let (k, v, b) = tmpfile("test_store_migrate_baseline");
+        // Write the files as the version before superblocks did:
+        // a full buffer was flushed to the values file, 5 values are still buffered
+        let count = 65536 + 5;
+        let mut keys = vec![0u8; 2 * kv::BASELINE_KEY_FILE_SIZE];
+        let mut values = vec![0u8; 65536 * kv::BASELINE_VALUE_SIZE];
+        let mut buffer = vec![0u8; kv::BASELINE_BUFFER_SIZE];
+        for i in 0..count {
+            let record = &mut keys[i * kv::BASELINE_KEY_SIZE..(i + 1) * kv::BASELINE_KEY_SIZE];
+            record[..7].copy_from_slice(format!("key{:04}", i % 1000).as_bytes());
+            record[8..].copy_from_slice(&(i as u32).to_be_bytes());
+            let slot = if i < 65536 {
+                &mut values[i * 256..(i + 1) * 256]
+            } else {
+                &mut buffer[(i - 65536) * 256..(i - 65535) * 256]
+            };
+            if i == count - 1 {
+                // Deleted
+                slot.copy_from_slice(&[0xff; 256]);
+            } else {
+                let value = format!("value{}", i);
+                slot[..value.len()].copy_from_slice(value.as_bytes());
+            }
+        }
+        fs::write(&k, &keys).unwrap();
+        fs::write(&v, &values).unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&v)
+            .unwrap()
+            .set_len(2 * values.len() as u64)
+            .unwrap();
+        fs::write(&b, &buffer).unwrap();
+
+        for _ in 0..2 {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            for path in &[&k, &v, &b] {
+                assert!(fs::read(path).unwrap().starts_with(superblock::MAGIC));
+            }
+            assert_eq!(db.get(b"key0999").unwrap().unwrap(), b"value64999");
+            assert_eq!(db.get(b"key0535").unwrap().unwrap(), b"value65535");
+            assert_eq!(db.get(b"key0539").unwrap().unwrap(), b"value65539");
+            assert_eq!(db.get(b"key0540").unwrap(), None);
+            assert_eq!(db.scan().count(), 999);
+        }
+
+        // Ventries continue after the migrated ones
+        let db = store::Store::new(&k, &v, &b).unwrap();
+        let snapshot = db.snapshot();
+        db.put(b"key0999", b"new").unwrap();
+        assert_eq!(snapshot.get(b"key0999").unwrap().unwrap(), b"value64999");
+        assert_eq!(db.get(b"key0999").unwrap().unwrap(), b"new");
+    }
+
+    #[test]
+    fn store_ventry_limit() {
+        let (k, v, b) = tmpfile("test_store_ventry_limit");
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key00", b"value00").unwrap();
+        }
+        // Pretend all but two ventries are used
+        let header = superblock::SUPERBLOCK_SIZE as usize;
+        let mut keys = fs::read(&k).unwrap();
+        let mut key = kv::key_from_bytes(&keys[header..]).unwrap().unwrap();
+        key.ventry = kv::MAX_VENTRY - 2;
+        let bytes = kv::key_to_bytes(&key);
+        keys[header..header + bytes.len()].copy_from_slice(&bytes);
+        fs::write(&k, &keys).unwrap();
+
+        let mut db = store::Store::new(&k, &v, &b).unwrap();
+        let mut batch = batch::WriteBatch::new();
+        batch.put(b"key01", b"value01").put(b"key02", b"value02");
+        batch.put(b"key03", b"value03");
+        assert_eq!(db.write(batch).err().unwrap(), error::Error::LimitReached);
+        db.put(b"key01", b"value01").unwrap();
+        db.put(b"key02", b"value02").unwrap();
+        assert_eq!(
+            db.put(b"key03", b"value03").err().unwrap(),
+            error::Error::LimitReached
+        );
+        assert_eq!(db.scan().count(), 3);
+        // Compaction renumbers the ventries
+        db.compact().unwrap();
+        db.put(b"key03", b"value03").unwrap();
+        assert_eq!(db.scan().count(), 4);
+    }
+
+    #[test]
+    fn store_superblock() {
+        let (k, v, b) = tmpfile("test_store_superblock");
+        {
+            let db = store::Store::new(&k, &v, &b).unwrap();
+            db.put(b"key", b"value").unwrap();
+        }
+        for path in &[&k, &v, &b] {
+            assert!(fs::read(path).unwrap().starts_with(superblock::MAGIC));
+        }
+
+        // Files swapped by mistake
+        let err = store::Store::new(&v, &k, &b).err().unwrap();
+        assert_eq!(
+            err,
+            error::Error::InvalidSuperblock {
+                file: k.clone(),
+                reason: "keys file, expected a values file".to_owned(),
+            }
+        );
+
+        // Created with another buffer size
+        let mut other = superblock::Superblock::new(
+            superblock::FileKind::Buffer,
+            &options::StoreOptions::default(),
+        );
+        other.buffer_size /= 2;
+        let mut buffer = fs::read(&b).unwrap();
+        let header = superblock::SUPERBLOCK_SIZE as usize;
+        buffer[..header].copy_from_slice(&other.to_bytes());
+        fs::write(&b, &buffer).unwrap();
+        let err = store::Store::new(&k, &v, &b).err().unwrap();
+        assert_eq!(
+            err,
+            error::Error::InvalidSuperblock {
+                file: b.clone(),
+                reason: format!(
+                    "buffer size {}, expected {}",
+                    kv::BUFFER_SIZE / 2,
+                    kv::BUFFER_SIZE
+                ),
+            }
+        );
+
+        // Not a db file at all
+        fs::write(&b, b"not a db file").unwrap();
+        let err = store::Store::new(&k, &v, &b).err().unwrap();
+        assert_eq!(
+            err,
+            error::Error::InvalidSuperblock {
+                file: b,
+                reason: "not a toy-kv file".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn store_options() {
+        let tmp = tempdir().unwrap().into_path();
+        let value = vec![7u8; 4000];
+        {
+            let db = store::Store::builder()
+                .buffer_size(8192)
+                .section_size(8192)
+                .alignment(512)
+                .sync(options::SyncPolicy::Always)
+                .file_names("a.k", "a.v", "a.b")
+                .open(&tmp)
+                .unwrap();
+            assert_eq!(
+                db.put(b"big", &[0; 8192]).err().unwrap(),
+                error::Error::ContentExceed
+            );
+            // Two values per buffer, a new section every 200 keys or so
+            for i in 0..500 {
+                let key = format!("key{:03}", i);
+                db.put(key.as_bytes(), &value).unwrap();
+            }
+        }
+        let header = superblock::SUPERBLOCK_SIZE;
+        let size = |name: &str| fs::metadata(tmp.join(name)).unwrap().len() - header;
+        assert_eq!(size("a.b"), 8192);
+        assert_eq!(size("a.v"), 249 * 8192);
+        assert_eq!(size("a.k") % 8192, 0);
+
+        // Reopened with the geometry it was created with
+        let mut db = store::Store::builder()
+            .file_names("a.k", "a.v", "a.b")
+            .open(&tmp)
+            .unwrap();
+        assert_eq!(db.options().buffer_size, 8192);
+        assert_eq!(db.options().section_size, 8192);
+        assert_eq!(db.scan().count(), 500);
+        assert_eq!(db.get(b"key499").unwrap().unwrap(), value);
+        db.put(b"key500", &value).unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get(b"key500").unwrap().unwrap(), value);
+        assert_eq!(db.scan().count(), 501);
+
+        let err = store::Store::builder()
+            .buffer_size(1000)
+            .open(tmp.join("other"))
+            .err()
+            .unwrap();
+        assert!(matches!(err, error::Error::InvalidOptions { .. }));
+    }
+
+    #[test]
+    fn store_open_locked() {
+        let tmp = tempdir().unwrap().into_path();
+        let dir = tmp.join("db");
+        let locked = error::Error::Locked {
+            file: dir.join("LOCK"),
+        };
+        {
+            let mut db = store::Store::open(&dir).unwrap();
+            db.put(b"key", b"value").unwrap();
+            for name in &["toy.k", "toy.v", "toy.b", "LOCK"] {
+                assert!(dir.join(name).exists());
+            }
+            assert_eq!(store::Store::open(&dir).err().unwrap(), locked);
+            // Still held after compaction reopens the files
+            db.compact().unwrap();
+            assert_eq!(store::Store::open(&dir).err().unwrap(), locked);
+        }
+        // Released on drop
+        let db = store::Store::open(&dir).unwrap();
+        assert_eq!(db.get(b"key").unwrap().unwrap(), b"value");
+        drop(db);
+
+        // Tools not changing the directory only lock it if it has a lock file
+        assert!(util::lock_existing_dir(&tmp).unwrap().is_none());
+        assert!(!tmp.join("LOCK").exists());
+        {
+            let _db = store::Store::open(&dir).unwrap();
+            assert_eq!(util::lock_existing_dir(&dir).err().unwrap(), locked);
+        }
+        assert!(util::lock_existing_dir(&dir).unwrap().is_some());
+
+        let sharded = shard::ShardedStore::new(&tmp, 2).unwrap();
+        assert_eq!(
+            shard::ShardedStore::new(&tmp, 2).err().unwrap(),
+            error::Error::Locked {
+                file: tmp.join("LOCK"),
+            }
+        );
+        drop(sharded);
+        assert!(shard::ShardedStore::new(&tmp, 2).is_ok());
+    }
+
+    #[test]
+    fn store_checkpoint() {
+        let tmp = tempdir().unwrap().into_path();
+        let backup = tmp.join("backup");
+        let db = options::StoreBuilder::new()
+            .buffer_size(8192)
+            .open(tmp.join("db"))
+            .unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), &[i as u8; 512])
+                .unwrap();
+        }
+        db.delete(b"key000").unwrap();
+        assert_eq!(db.checkpoint(&backup).unwrap(), 101);
+        assert!(db.checkpoint(&backup).is_err());
+        assert!(!tmp.join("backup.compact").exists());
+        // The store keeps serving writes, the copy does not see them
+        db.put(b"key001", b"new").unwrap();
+        db.put(b"key100", b"new").unwrap();
+        assert_eq!(db.get(b"key001").unwrap().unwrap(), b"new");
+
+        let names = ["toy.k", "toy.v", "toy.b", "toy.k.idx"];
+        for name in names.iter() {
+            assert!(backup.join(name).exists());
+        }
+        let copy = store::Store::new(
+            backup.join("toy.k"),
+            backup.join("toy.v"),
+            backup.join("toy.b"),
+        )
+        .unwrap();
+        assert_eq!(copy.options().buffer_size, 8192);
+        assert_eq!(copy.get(b"key000").unwrap(), None);
+        assert_eq!(copy.get(b"key001").unwrap().unwrap(), vec![1; 512]);
+        assert_eq!(copy.get(b"key099").unwrap().unwrap(), vec![99; 512]);
+        assert_eq!(copy.get(b"key100").unwrap(), None);
+        assert_eq!(copy.scan().count(), 99);
+    }
+
+    #[test]
+    fn store_checkpoint_concurrent() {
+        let tmp = tempdir().unwrap().into_path();
+        let db = Arc::new(
+            options::StoreBuilder::new()
+                .buffer_size(8192)
+                .section_size(4096)
+                .open(tmp.join("db"))
+                .unwrap(),
+        );
+        // A checkpoint which crashed left its tmp directory
+        fs::create_dir_all(tmp.join("backup.compact")).unwrap();
+        fs::write(tmp.join("backup.compact").join("toy.k"), b"partial").unwrap();
+
+        // The copy is taken while another thread writes
+        let writer = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for i in 0..300 {
+                    db.put(format!("key{:03}", i).as_bytes(), &[i as u8; 100])
+                        .unwrap();
+                }
+            })
+        };
+        while db.snapshot().get(b"key010").unwrap().is_none() {
+            thread::yield_now();
+        }
+        let ventry = db.checkpoint(tmp.join("backup")).unwrap();
+        writer.join().unwrap();
+        assert!(!tmp.join("backup.compact").exists());
+
+        let dir = tmp.join("backup");
+        let copy =
+            store::Store::new(dir.join("toy.k"), dir.join("toy.v"), dir.join("toy.b")).unwrap();
+        assert_eq!(copy.scan().count() as u64, ventry);
+        for i in 0..300 {
+            let value = copy.get(format!("key{:03}", i).as_bytes()).unwrap();
+            assert_eq!(value.is_some(), i < ventry, "{}", i);
+        }
+        // Reopened without the index checkpoint too
+        drop(copy);
+        fs::remove_file(dir.join("toy.k.idx")).unwrap();
+        let copy =
+            store::Store::new(dir.join("toy.k"), dir.join("toy.v"), dir.join("toy.b")).unwrap();
+        assert_eq!(copy.scan().count() as u64, ventry);
+        let report = fsck::check(dir.join("toy.k"), dir.join("toy.v"), dir.join("toy.b")).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn store_incremental_backup() {
+        let tmp = tempdir().unwrap().into_path();
+        let deltas: Vec<PathBuf> = (0..3).map(|i| tmp.join(format!("{}.delta", i))).collect();
+        let db = options::StoreBuilder::new()
+            .buffer_size(8192)
+            .open(tmp.join("db"))
+            .unwrap();
+        for i in 0..50 {
+            db.put(format!("key{:03}", i).as_bytes(), &[i as u8; 512])
+                .unwrap();
+        }
+        let base = db.checkpoint(tmp.join("base")).unwrap();
+        assert_eq!(base, 50);
+
+        db.delete(b"key000").unwrap();
+        db.put_with_ttl(b"ttl", b"value", Duration::from_secs(3600))
+            .unwrap();
+        let mut batch = batch::WriteBatch::new();
+        batch.put(b"key001", b"batch").delete(b"key002");
+        db.write(batch).unwrap();
+        let watermark = db.export_delta(base, &deltas[0]).unwrap();
+        assert_eq!(watermark, 54);
+        assert!(db.export_delta(base, &deltas[0]).is_err());
+
+        for i in 40..60 {
+            db.put(format!("key{:03}", i).as_bytes(), b"new").unwrap();
+        }
+        assert_eq!(db.export_delta(watermark, &deltas[1]).unwrap(), 74);
+        // Nothing written since
+        assert_eq!(db.export_delta(74, &deltas[2]).unwrap(), 74);
+        assert_eq!(
+            db.export_delta(75, tmp.join("ahead.delta")).err().unwrap(),
+            error::Error::InvalidDelta {
+                file: tmp.join("ahead.delta"),
+                reason: "watermark 75 is ahead of the store at 74".to_owned(),
+            }
+        );
+
+        let open_base = || {
+            let dir = tmp.join("base");
+            store::Store::new(dir.join("toy.k"), dir.join("toy.v"), dir.join("toy.b")).unwrap()
+        };
+        {
+            // A chain out of order is refused before writing anything
+            let mut copy = open_base();
+            assert_eq!(
+                copy.restore(&[&deltas[1], &deltas[0]]).err().unwrap(),
+                error::Error::InvalidDelta {
+                    file: deltas[1].clone(),
+                    reason: "starts at ventry 54, expected 50".to_owned(),
+                }
+            );
+            assert_eq!(copy.get(b"key000").unwrap().unwrap(), vec![0; 512]);
+            assert_eq!(copy.restore(&deltas[..2]).unwrap(), 74);
+            assert!(copy.restore(&deltas[..1]).is_err());
+        }
+        // Restored writes persist, and the chain goes on from there
+        let mut copy = open_base();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = db.scan().map(Result::unwrap).collect();
+        let restored: Vec<(Vec<u8>, Vec<u8>)> = copy.scan().map(Result::unwrap).collect();
+        assert_eq!(restored, expected);
+        assert_eq!(copy.get(b"key000").unwrap(), None);
+        assert_eq!(copy.get(b"key001").unwrap().unwrap(), b"batch");
+        assert_eq!(copy.get(b"ttl").unwrap().unwrap(), b"value");
+        assert_eq!(copy.restore(&deltas[2..]).unwrap(), 74);
+        assert_eq!(copy.snapshot().get(b"key059").unwrap().unwrap(), b"new");
+    }
+
+    #[test]
+    fn store_export_concurrent() {
+        let tmp = tempdir().unwrap().into_path();
+        let (k, v, b) = tmpfile("test_store_export_concurrent");
+        let options = options::StoreOptions {
+            buffer_size: 8192,
+            section_size: 4096,
+            ..options::StoreOptions::default()
+        };
+        let db = Arc::new(store::Store::with_options(&k, &v, &b, options).unwrap());
+        // Deltas are exported while records are copied into the mapped keys
+        let writer = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for i in 0..300 {
+                    db.put(format!("key{:03}", i).as_bytes(), &[i as u8; 100])
+                        .unwrap();
+                }
+            })
+        };
+        let (mut since, mut count, mut n) = (0, 0, 0);
+        let mut export = |since: u64| {
+            let path = tmp.join(format!("{}.delta", n));
+            n += 1;
+            let to = db.export_delta(since, &path).unwrap();
+            let header = delta::DeltaReader::open(&path).unwrap().header().clone();
+            (to, header.count)
+        };
+        while !writer.is_finished() {
+            let (to, records) = export(since);
+            since = to;
+            count += records;
+        }
+        writer.join().unwrap();
+        let (to, records) = export(since);
+        assert_eq!(to, 300);
+        assert_eq!(count + records, 300);
+
+        // Records before the index checkpoint are not read for a delta after it
+        db.save_index().unwrap();
+        db.put(b"key300", b"new").unwrap();
+        let mut keys = fs::read(&k).unwrap();
+        keys[superblock::SUPERBLOCK_SIZE as usize + kv::KEY_HEADER_SIZE] ^= 0xff;
+        fs::write(&k, &keys).unwrap();
+        let (to, records) = export(300);
+        assert_eq!((to, records), (301, 1));
+        assert_eq!(
+            db.export_delta(0, tmp.join("all.delta")).err().unwrap(),
+            error::Error::Corrupted {
+                file: k.clone(),
+                offset: superblock::SUPERBLOCK_SIZE,
+            }
+        );
+    }
+
+    #[test]
+    fn store_fsck() {
+        let (k, v, b) = tmpfile("test_store_fsck");
+        let header = superblock::SUPERBLOCK_SIZE;
+        let options = options::StoreOptions {
+            buffer_size: 8192,
+            section_size: 4096,
+            ..options::StoreOptions::default()
+        };
+        {
+            let db = store::Store::with_options(&k, &v, &b, options).unwrap();
+            for i in 0..100 {
+                db.put(format!("key{:03}", i).as_bytes(), &[i as u8; 100])
+                    .unwrap();
+            }
+            db.delete(b"key000").unwrap();
+            let mut batch = batch::WriteBatch::new();
+            batch.put(b"key001", b"batch").delete(b"key002");
+            db.write(batch).unwrap();
+        }
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(report.sections, 2);
+        assert_eq!(report.keys, 103);
+        assert_eq!(report.tombstones, 2);
+        assert_eq!(report.value_bytes, 100 * 100 + 5);
+
+        let keys = fs::read(&k).unwrap();
+        let mut corrupted = keys.clone();
+        corrupted[header as usize + 40] ^= 1;
+        fs::write(&k, &corrupted).unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.issues[0].offset, Some(header));
+        assert!(report.issues[0].message.starts_with("corrupted key record"));
+
+        // Half a section
+        fs::write(&k, &keys[..keys.len() - 2048]).unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert_eq!(
+            report.issues[0],
+            fsck::Issue {
+                severity: fsck::Severity::Error,
+                file: k.clone(),
+                offset: Some(header + 4096),
+                message: "truncated section, 2048 of 4096 bytes".to_owned(),
+            }
+        );
+        fs::write(&k, &keys).unwrap();
+
+        // Values of the flushed buffer lost
+        let values = fs::read(&v).unwrap();
+        assert_eq!(values.len(), header as usize + 8192);
+        fs::write(&v, &values[..header as usize]).unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert!(report.errors() > 0);
+        assert!(report
+            .issues
+            .iter()
+            .any(|issue| issue.message.contains("out of the values file")));
+        fs::write(&v, &values[..values.len() - 100]).unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert_eq!(report.issues[0].severity, fsck::Severity::Warning);
+        assert!(report.issues[0].message.contains("a flush was cut short"));
+        fs::write(&v, &values).unwrap();
+
+        // Values of the buffer cut short are reported, the check goes on
+        let buffer = fs::read(&b).unwrap();
+        fs::write(&b, &buffer[..header as usize + 1024]).unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert_eq!(report.keys, 103);
+        assert!(report
+            .issues
+            .iter()
+            .any(|issue| issue.message.contains("past the end of the file")));
+
+        fs::write(&b, b"not a buffer").unwrap();
+        let report = fsck::check(&k, &v, &b).unwrap();
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.sections, 0);
+        assert_eq!(report.issues[0].message, "not a toy-kv file");
+    }
+
+    #[test]
+    fn store_binary_safe() {
+        let tmp = tempdir().unwrap().into_path();
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![0], vec![]),
+            (vec![0, 0, 0], vec![0; 256]),
+            (vec![0, 1, 0], vec![0, 0, 1, 0, 0]),
+            (vec![0xff], vec![0xff; 256]),
+            (vec![0xff; 1024], vec![0xff; 8]),
+            (b"key\0".to_vec(), vec![0xff, 0, 0xff]),
+        ];
+        let check = |db: &store::Store| {
+            for (key, value) in pairs.iter() {
+                assert_eq!(&db.get(key).unwrap().unwrap(), value);
+            }
+            let scanned: Vec<(Vec<u8>, Vec<u8>)> = db.scan().map(Result::unwrap).collect();
+            let mut expected = pairs.clone();
+            expected.sort();
+            assert_eq!(scanned, expected);
+        };
+        {
+            // A small buffer, so that values are flushed to the values file
+            let db = store::Store::builder()
+                .buffer_size(4096)
+                .open(&tmp)
+                .unwrap();
+            for (key, value) in pairs.iter() {
+                db.put(key, value).unwrap();
+                db.put(b"filler", &[0; 3000]).unwrap();
             }
-            // assert_eq!(v.to_string(), "v65536");
+            db.delete(b"filler").unwrap();
+            check(&db);
         }
+        let mut db = store::Store::open(&tmp).unwrap();
+        check(&db);
+        db.compact().unwrap();
+        check(&db);
+        assert_eq!(
+            kv::InnerKey::from(&b"\0key\0"[..]).to_string(),
+            "\u{0}key\u{0}"
+        );
     }
 }
@@ -0,0 +1,73 @@
+use toy_kv::engine::delta::DeltaReader;
+use toy_kv::engine::error::Error;
+use toy_kv::engine::kv::Value;
+use toy_kv::engine::store::Store;
+
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage:
+\t toy-delta export [db dir] [since] [delta file]
+\t toy-delta restore [db dir] [delta file...]
+\t toy-delta show [delta file]";
+
+/// Incremental backups of the store under a directory, see `Store::export_delta`
+/// A store served by `toy-kv` is exported through the client(`Export`) instead,
+/// the directory is locked while it is open.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = match (args.get(1).map(String::as_str), args.len()) {
+        (Some("export"), 5) => match args[3].parse::<u64>() {
+            Ok(since) => export(&args[2], since, &args[4]),
+            Err(_) => usage(),
+        },
+        (Some("restore"), n) if n > 3 => restore(&args[2], &args[3..]),
+        (Some("show"), 3) => show(&args[2]),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn usage() -> Result<(), Error> {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn export(dir: &str, since: u64, delta: &str) -> Result<(), Error> {
+    let store = Store::open(dir)?;
+    let to = store.export_delta(since, delta)?;
+    println!("{}: ventries {} to {}", delta, since, to);
+    println!("next watermark: {}", to);
+    Ok(())
+}
+
+fn restore(dir: &str, deltas: &[String]) -> Result<(), Error> {
+    let mut store = Store::open(dir)?;
+    let ventry = store.restore(deltas)?;
+    println!(
+        "{} delta(s) applied, {} at ventry {}",
+        deltas.len(),
+        dir,
+        ventry
+    );
+    Ok(())
+}
+
+fn show(delta: &str) -> Result<(), Error> {
+    let reader = DeltaReader::open(delta)?;
+    let header = reader.header().clone();
+    let mut bytes = 0;
+    for record in reader {
+        if let (_, Value::Valid(value)) = record? {
+            bytes += value.len() as u64;
+        }
+    }
+    println!(
+        "{}: version {}, ventries {} to {}, {} records, {} value bytes",
+        delta, header.version, header.from, header.to, header.count, bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use toy_kv::engine::error::Error;
+use toy_kv::engine::fsck::{self, Report};
+use toy_kv::engine::options::{DEFAULT_BUFFER_FILE, DEFAULT_KEY_FILE, DEFAULT_VALUE_FILE};
+use toy_kv::engine::util;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+/// Exit code: the files are consistent
+const CLEAN: i32 = 0;
+/// Exit code: only warnings, repaired or ignored when opening the store
+const WARNINGS: i32 = 1;
+/// Exit code: errors, writes are lost or the store fails to open
+const ERRORS: i32 = 2;
+/// Exit code: the files could not be checked
+const FAILED: i32 = 3;
+
+const USAGE: &str = "Usage:
+\t toy-fsck [db dir]
+\t toy-fsck [keys file] [values file] [buffer file]";
+
+/// Offline check of a store, see `fsck::check`
+/// A directory is locked while it is checked(if it has a lock file), so an open store is not checked.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = match args.len() {
+        2 => check_dir(Path::new(&args[1])),
+        4 => fsck::check(&args[1], &args[2], &args[3]),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(FAILED)
+        }
+    };
+    match result {
+        Ok(report) => process::exit(print(&report)),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(FAILED)
+        }
+    }
+}
+
+fn check_dir(dir: &Path) -> Result<Report, Error> {
+    if !dir.join(DEFAULT_KEY_FILE).exists() {
+        return Err(Error::IoError(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no store in {:?}", dir),
+        )));
+    }
+    // Without creating a lock file, the files are not changed
+    let _lock = util::lock_existing_dir(dir)?;
+    fsck::check(
+        dir.join(DEFAULT_KEY_FILE),
+        dir.join(DEFAULT_VALUE_FILE),
+        dir.join(DEFAULT_BUFFER_FILE),
+    )
+}
+
+/// Print the report, returns the exit code
+fn print(report: &Report) -> i32 {
+    for issue in &report.issues {
+        println!("{}", issue);
+    }
+    println!(
+        "{} sections, {} keys ({} tombstones), {} value bytes: {} errors, {} warnings",
+        report.sections,
+        report.keys,
+        report.tombstones,
+        report.value_bytes,
+        report.errors(),
+        report.warnings()
+    );
+    if report.errors() > 0 {
+        ERRORS
+    } else if report.warnings() > 0 {
+        WARNINGS
+    } else {
+        CLEAN
+    }
+}